@@ -3,14 +3,19 @@ use std::{
     path::PathBuf,
     process::ExitCode,
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use clap::{Parser, Subcommand};
 use client::start_deamon;
 
-use anyhow::Result;
-use core::{is_daemon_running, objects::Objects};
+use anyhow::{Result, anyhow};
+use core::{
+    is_daemon_running,
+    messages::{CommandConnection, Reply},
+    objects::Objects,
+    status::DaemonStatus,
+};
 use seahash::SeaHasher;
 
 use colored::*;
@@ -32,29 +37,120 @@ enum Commands {
     Close {
         path: Option<PathBuf>,
     },
+    /// Starts the daemon, it reopens the projects it had open when it last stopped
+    Start,
     Shutdown,
+    /// Shows what the daemon is watching and how it's doing
+    Status {
+        /// Print the daemon's status as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Prints every change in the open projects as a line of json as it happens
+    Subscribe {
+        /// Only changes in this project, can be given more than once
+        #[arg(long = "project")]
+        projects: Vec<PathBuf>,
+        /// Only changes under this path relative to the project, can be given more than once
+        #[arg(long = "path")]
+        paths: Vec<PathBuf>,
+    },
 }
 
-fn success(msg: &str) -> () {
+fn success(msg: &str) {
     let prefix = "[success]".green().bold();
     println!("{prefix} {msg}")
 }
-fn error(msg: &str) -> () {
+fn error(msg: &str) {
     let prefix = "[error]".red().bold();
     println!("{prefix} {msg}")
 }
-fn info(msg: &str) -> () {
+fn info(msg: &str) {
     let i_prefix = "[info]".blue().bold();
     println!("{i_prefix} {msg}")
 }
 
+/// Prints how a command went, the daemon's reason when it failed.
+fn report(reply: Reply, done: &str) -> ExitCode {
+    match reply {
+        Reply::Error { message, .. } => {
+            error(&message);
+            ExitCode::FAILURE
+        }
+        Reply::Ok | Reply::Data { .. } => {
+            success(done);
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// How long ago a time was, roughly
+fn ago(time: SystemTime) -> String {
+    let elapsed = time.elapsed().unwrap_or_default().as_secs();
+    match elapsed {
+        0..60 => format!("{elapsed}s ago"),
+        60..3600 => format!("{}m ago", elapsed / 60),
+        _ => format!("{}h ago", elapsed / 3600),
+    }
+}
+
+fn print_status(status: &DaemonStatus) {
+    let usage = &status.usage;
+    let cpu = usage.cpu_user + usage.cpu_system;
+    let memory = usage
+        .memory
+        .map(|bytes| format!(", {:.1} MiB", bytes as f64 / (1024.0 * 1024.0)))
+        .unwrap_or_default();
+    info(&format!(
+        "daemon {} started {}, cpu {:.1}s{memory}",
+        status.pid,
+        ago(status.started),
+        cpu.as_secs_f64()
+    ));
+    if status.projects.is_empty() {
+        info("no projects open");
+    }
+    for project in &status.projects {
+        let files = project
+            .watch
+            .files
+            .map(|files| format!("{files} files"))
+            .unwrap_or("not scanned".to_string());
+        let scanned = project
+            .watch
+            .last_scan
+            .map(|at| format!(", scanned {}", ago(at)))
+            .unwrap_or_default();
+        let synced = project
+            .last_sync
+            .map(|at| format!(", synced {}", ago(at)))
+            .unwrap_or_default();
+        println!(
+            "{} {files}{scanned}, {:?}, {} pending{synced}",
+            project.root.display().to_string().bold(),
+            project.connection,
+            project.pending
+        );
+        if let Some(problem) = &project.watch.error {
+            error(problem);
+        }
+        if let (Some(problem), Some(retry_at)) = (&project.last_error, project.retry_at) {
+            let wait = retry_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            error(&format!("{problem}, retrying in {}s", wait.as_secs()));
+        }
+    }
+}
+
 fn start_daemon_if_not_running(user: &str) -> Result<()> {
     if !is_daemon_running() {
-        start_deamon(&user)?;
+        start_deamon(user)?;
         success("starting daemon");
         // todo: need a spinner
         loop {
-            if is_daemon_running() {
+            // the pid file shows up before the daemon is listening for commands
+            if is_daemon_running() && CommandConnection::connect().is_ok() {
                 break;
             }
             thread::sleep(Duration::from_millis(100));
@@ -84,22 +180,67 @@ fn main() -> Result<ExitCode> {
             start_daemon_if_not_running(&user)?;
             info("open");
             let path = path.unwrap_or(env::current_dir()?);
-            core::messages::Command::Open { path }.send()?;
-            Result::Ok(ExitCode::SUCCESS)
+            let reply = core::messages::Command::Open { path: path.clone() }.send()?;
+            Result::Ok(report(reply, &format!("opened {}", path.display())))
         }
         Commands::Close { path } => {
             start_daemon_if_not_running(&user)?;
             info("close");
             let path = path.unwrap_or(env::current_dir()?);
-            core::messages::Command::Close { path }.send()?;
+            let reply = core::messages::Command::Close { path: path.clone() }.send()?;
+            Result::Ok(report(reply, &format!("closed {}", path.display())))
+        }
+        Commands::Start => {
+            if is_daemon_running() {
+                info("daemon already running");
+            }
+            start_daemon_if_not_running(&user)?;
+            Result::Ok(ExitCode::SUCCESS)
+        }
+        Commands::Status { json } => {
+            if !is_daemon_running() {
+                error("daemon not running");
+                return Result::Ok(ExitCode::FAILURE);
+            }
+            let reply = core::messages::Command::Status.send()?;
+            if json {
+                match reply {
+                    Reply::Data { data } => println!("{}", serde_json::to_string_pretty(&data)?),
+                    reply => return Result::Ok(report(reply, "")),
+                }
+            } else if let Some(status) = reply.into_result::<DaemonStatus>()? {
+                print_status(&status);
+            }
+            Result::Ok(ExitCode::SUCCESS)
+        }
+        Commands::Subscribe { projects, paths } => {
+            if !is_daemon_running() {
+                error("daemon not running");
+                return Result::Ok(ExitCode::FAILURE);
+            }
+            // the daemon knows projects by their full path, `--project .` has to be made one
+            let projects = projects
+                .iter()
+                .map(|project| {
+                    std::fs::canonicalize(project)
+                        .map_err(|err| anyhow!("can't find project {project:?}: {err}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for line in CommandConnection::connect()?.subscribe(projects, paths)? {
+                println!("{}", line?);
+            }
             Result::Ok(ExitCode::SUCCESS)
         }
         Commands::Shutdown => {
             if is_daemon_running() {
-                core::messages::Command::Shutdown {
+                let reply = core::messages::Command::Shutdown {
                     caller: "cli".to_string(),
                 }
                 .send()?;
+                if let Reply::Error { message, .. } = reply {
+                    error(&message);
+                    return Result::Ok(ExitCode::FAILURE);
+                }
                 info("shutdown sent, waiting...");
                 loop {
                     // wait until the daemon is dead
@@ -110,10 +251,10 @@ fn main() -> Result<ExitCode> {
                 }
                 success("daemon shutdown");
                 // todo: We need to connect to the daemon server and await it's shutdown
-                return Result::Ok(ExitCode::SUCCESS);
+                Result::Ok(ExitCode::SUCCESS)
             } else {
                 info("daemon not running, no shutdown required");
-                return Result::Ok(ExitCode::FAILURE);
+                Result::Ok(ExitCode::FAILURE)
             }
         }
     }
@@ -142,11 +283,3 @@ fn open_command() -> Result<&'static str, &'static str> {
 fn not_implemented() -> Result<&'static str, &'static str> {
     Err("not yet been implemented")
 }
-
-fn parse_operand(command: &str) -> Result<Command, &'static str> {
-    match command.to_lowercase().as_str() {
-        "open" => Ok(Command::Open),
-        "close" => Ok(Command::Close),
-        _ => Err("not a known command"),
-    }
-}
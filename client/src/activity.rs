@@ -0,0 +1,178 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    time::{Instant, SystemTime},
+};
+
+use core::{
+    messages::Feed,
+    status::{DaemonStatus, EventRecord, Origin, ProjectStatus, Stats, Usage},
+    watcher::{ChangeEvent, ChangeKind, Watcher},
+};
+use tokio::sync::mpsc;
+
+use crate::link::Links;
+
+/// How many of the most recent changes are kept for `Events`
+const RECENT_EVENTS: usize = 200;
+/// How far a subscriber can fall behind before it's cut off
+const FEED_BACKLOG: usize = 256;
+
+/// Someone listening for changes, empty projects or paths match everything.
+struct Subscriber {
+    projects: Vec<PathBuf>,
+    /// Relative to the project root, a path matches everything under it
+    paths: Vec<PathBuf>,
+    sender: mpsc::Sender<EventRecord>,
+}
+
+impl Subscriber {
+    fn wants(&self, event: &EventRecord) -> bool {
+        let project = self.projects.is_empty() || self.projects.contains(&event.root);
+        let path = self.paths.is_empty()
+            || event
+                .kind
+                .paths()
+                .iter()
+                .any(|path| self.paths.iter().any(|wanted| path.starts_with(wanted)));
+        project && path
+    }
+}
+
+/// What the daemon's been up to, answers the introspection commands.
+pub struct Activity {
+    started: SystemTime,
+    uptime: Instant,
+    recent: VecDeque<EventRecord>,
+    events: u64,
+    remote_events: u64,
+    errors: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            started: SystemTime::now(),
+            uptime: Instant::now(),
+            recent: VecDeque::new(),
+            events: 0,
+            remote_events: 0,
+            errors: 0,
+            subscribers: Vec::new(),
+        }
+    }
+}
+
+impl Activity {
+    pub fn record(&mut self, event: &ChangeEvent) {
+        self.events += 1;
+        self.publish(EventRecord {
+            root: event.root.clone(),
+            kind: event.kind.clone(),
+            at: event.at,
+            origin: Origin::Local,
+        });
+    }
+
+    /// A change from the server was written into the project at root
+    pub fn record_remote(&mut self, root: PathBuf, kind: ChangeKind) {
+        self.remote_events += 1;
+        self.publish(EventRecord {
+            root,
+            kind,
+            at: SystemTime::now(),
+            origin: Origin::Remote,
+        });
+    }
+
+    pub fn record_error(&mut self) {
+        self.errors += 1;
+    }
+
+    pub async fn status<W: Watcher + Sync>(&self, watcher: &W, links: &Links) -> DaemonStatus {
+        let projects = watcher
+            .watched()
+            .await
+            .into_iter()
+            .map(|watched| {
+                let link = links.status(&watched.root);
+                ProjectStatus {
+                    root: watched.root,
+                    watch: watched.status,
+                    connection: link.connection,
+                    pending: link.pending,
+                    last_sync: link.last_sync,
+                    last_error: link.last_error,
+                    retry_at: link.retry_at,
+                }
+            })
+            .collect();
+        DaemonStatus {
+            pid: std::process::id(),
+            started: self.started,
+            projects,
+            usage: Usage::current(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            uptime: self.uptime.elapsed(),
+            events: self.events,
+            remote_events: self.remote_events,
+            errors: self.errors,
+            usage: Usage::current(),
+        }
+    }
+
+    /// Starts sending every change matching the projects and paths to the returned feed.
+    pub fn subscribe(&mut self, projects: Vec<PathBuf>, paths: Vec<PathBuf>) -> Feed {
+        let (sender, feed) = mpsc::channel(FEED_BACKLOG);
+        self.subscribers.push(Subscriber {
+            projects,
+            paths,
+            sender,
+        });
+        feed
+    }
+
+    /// Keeps the event for `Events` and hands it to subscribers, any that have hung up or can't
+    /// keep up are dropped which ends their feed.
+    fn publish(&mut self, event: EventRecord) {
+        self.subscribers.retain(|subscriber| {
+            !subscriber.sender.is_closed()
+                && (!subscriber.wants(&event) || subscriber.sender.try_send(event.clone()).is_ok())
+        });
+        if self.recent.len() == RECENT_EVENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(event);
+    }
+
+    /// The most recent events, oldest first
+    pub fn events(&self, limit: Option<usize>) -> Vec<EventRecord> {
+        let skip = limit.map_or(0, |limit| self.recent.len().saturating_sub(limit));
+        self.recent.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers_only_get_what_they_asked_for() {
+        let mut activity = Activity::default();
+        let mut feed = activity.subscribe(vec!["/a".into()], vec!["src".into()]);
+        activity.record_remote("/a".into(), ChangeKind::Modified("src/lib.rs".into()));
+        activity.record_remote("/a".into(), ChangeKind::Modified("README.md".into()));
+        activity.record_remote("/b".into(), ChangeKind::Modified("src/lib.rs".into()));
+
+        let event = feed.try_recv().unwrap();
+        assert_eq!(event.kind, ChangeKind::Modified("src/lib.rs".into()));
+        assert_eq!(event.origin, Origin::Remote);
+        assert!(feed.try_recv().is_err());
+        assert_eq!(activity.events(None).len(), 3);
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::*;
+use core::{config::SINK_DIRECTORY, status::Origin, watcher::ChangeKind};
+use similar::TextDiff;
+use tokio::io::AsyncWriteExt;
+
+/// Files bigger than this are listed without a diff, and aren't kept around to diff against
+const MAX_DIFF: u64 = 256 * 1024;
+/// A log's rotated to `<name>.log.1` once it's grown past this
+const ROTATE_AFTER: u64 = 1024 * 1024;
+/// How many rotated logs are kept besides the one being written
+const KEEP: usize = 3;
+
+/// A human readable record of what happened to an open project, each local and remote change
+/// with a unified diff for text files. Appended to `.sink/logs/<name>.log`.
+struct ChangeLog {
+    root: PathBuf,
+    path: PathBuf,
+    /// How big the log is, it's rotated once it's past [`ROTATE_AFTER`]
+    size: u64,
+    /// Text files as of the last change to them, what the next change is diffed against
+    contents: HashMap<PathBuf, String>,
+}
+
+/// The change logs of every open project.
+#[derive(Default)]
+pub struct ChangeLogs {
+    logs: HashMap<PathBuf, ChangeLog>,
+}
+
+impl ChangeLogs {
+    /// Starts logging the project at root. Nothing's read up front, a file's kept to diff against
+    /// from the first change to it on, so the log never holds up opening the project.
+    pub async fn open(&mut self, root: &Path) {
+        let name = root
+            .file_name()
+            .map_or("project".into(), |name| name.to_string_lossy());
+        let path = root
+            .join(SINK_DIRECTORY)
+            .join("logs")
+            .join(format!("{name}.log"));
+        let size = tokio::fs::metadata(&path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        let log = ChangeLog {
+            root: root.to_path_buf(),
+            path,
+            size,
+            contents: HashMap::new(),
+        };
+        self.logs.insert(root.to_path_buf(), log);
+    }
+
+    pub fn close(&mut self, root: &Path) {
+        self.logs.remove(root);
+    }
+
+    /// Appends a change to the project at root to its log, the files are read as they are now.
+    pub async fn record(&mut self, root: &Path, kind: &ChangeKind, origin: Origin) -> Result<()> {
+        let Some(log) = self.logs.get_mut(root) else {
+            return Ok(());
+        };
+        match log.entry(kind, origin).await {
+            Some(entry) => log.append(&entry).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl ChangeLog {
+    /// What to log for a change, None when the file's content is as it was last logged. Editors
+    /// often touch a file more than once for one save.
+    async fn entry(&mut self, kind: &ChangeKind, origin: Origin) -> Option<String> {
+        let origin = match origin {
+            Origin::Local => "local",
+            Origin::Remote => "remote",
+        };
+        let mut entry = format!("{} {origin} ", timestamp(SystemTime::now()));
+        let (old, new) = match kind {
+            ChangeKind::Created(path) => {
+                let _ = writeln!(entry, "created ./{}", path.display());
+                (Some(String::new()), self.read(path).await)
+            }
+            ChangeKind::Modified(path) => {
+                let _ = writeln!(entry, "modified ./{}", path.display());
+                (self.contents.remove(path), self.read(path).await)
+            }
+            ChangeKind::Deleted(path) => {
+                let _ = writeln!(entry, "deleted ./{}", path.display());
+                (self.contents.remove(path), Some(String::new()))
+            }
+            ChangeKind::Renamed { from, to } => {
+                let _ = writeln!(entry, "renamed ./{} to ./{}", from.display(), to.display());
+                (self.contents.remove(from), self.read(to).await)
+            }
+        };
+        match (old, new) {
+            (Some(old), Some(new)) if old == new && matches!(kind, ChangeKind::Modified(_)) => {
+                return None;
+            }
+            (Some(old), Some(new)) => {
+                entry.push_str(&TextDiff::from_lines(&old, &new).unified_diff().to_string());
+            }
+            (None, Some(_)) => entry.push_str("no earlier copy to diff against\n"),
+            (_, None) => entry.push_str("not text, or too big to diff\n"),
+        }
+        Some(entry)
+    }
+
+    /// Reads a file for diffing and keeps it for the next change, None if it's not text or is
+    /// too big.
+    async fn read(&mut self, path: &Path) -> Option<String> {
+        let content = read_text(&self.root.join(path)).await?;
+        self.contents.insert(path.to_path_buf(), content.clone());
+        Some(content)
+    }
+
+    async fn append(&mut self, entry: &str) -> Result<()> {
+        if self.size > ROTATE_AFTER {
+            self.rotate().await?;
+        }
+        if let Some(directory) = self.path.parent() {
+            tokio::fs::create_dir_all(directory).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(entry.as_bytes()).await?;
+        // tokio's file writes in the background, it's only done once flushed
+        file.flush().await?;
+        self.size += entry.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `<name>.log.1` to `<name>.log.2` and so on, the oldest falls off the end.
+    async fn rotate(&mut self) -> Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+        for n in (1..KEEP).rev() {
+            match tokio::fs::rename(rotated(n), rotated(n + 1)).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        tokio::fs::rename(&self.path, rotated(1)).await?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// A file's content if it's text and small enough to diff
+async fn read_text(path: &Path) -> Option<String> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    if metadata.len() > MAX_DIFF {
+        return None;
+    }
+    String::from_utf8(tokio::fs::read(path).await.ok()?).ok()
+}
+
+/// UTC, to the second, like `2024-01-31 09:05:00Z`
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    // days since the epoch to a date, from Howard Hinnant's civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::testing::{TempDir, block_on};
+    use std::time::Duration;
+
+    #[test]
+    fn test_timestamps_are_utc_dates() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01 00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(timestamp(leap_day), "2024-02-29 12:34:56Z");
+    }
+
+    #[test]
+    fn test_changes_are_logged_with_a_diff() {
+        let root = TempDir::new("changelog");
+        let mut logs = ChangeLogs::default();
+        block_on(logs.open(&root));
+        let modified = ChangeKind::Modified("file.txt".into());
+
+        // nothing was read when the project opened
+        std::fs::write(root.join("file.txt"), "hello\n,\n").unwrap();
+        block_on(logs.record(&root, &modified, Origin::Local)).unwrap();
+        std::fs::write(root.join("file.txt"), "hello, world\n").unwrap();
+        block_on(logs.record(&root, &modified, Origin::Remote)).unwrap();
+
+        let name = root.file_name().unwrap().to_string_lossy();
+        let log = std::fs::read_to_string(root.join(format!(".sink/logs/{name}.log"))).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert!(lines[0].ends_with(" local modified ./file.txt"));
+        assert_eq!(lines[1], "no earlier copy to diff against");
+        assert!(lines[2].ends_with(" remote modified ./file.txt"));
+        assert_eq!(
+            lines[3..],
+            ["@@ -1,2 +1 @@", "-hello", "-,", "+hello, world"]
+        );
+    }
+}
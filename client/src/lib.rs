@@ -1,22 +1,38 @@
+pub mod activity;
+pub mod changelog;
+pub mod link;
+pub mod queue;
+pub mod remote;
+pub mod state;
+
+use core::config::ProjectConfig;
 use core::is_daemon_running;
 use core::messages::Command;
 use core::messages::CommandListener;
-use core::project::Project;
+use core::messages::Reply;
+use core::path_is_parent;
+use core::status::Origin;
 use daemonize::Daemonize;
 use seahash::SeaHasher;
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs::File;
-use std::fs::create_dir_all;
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Duration;
 use tokio::select;
 use tokio::signal::unix::SignalKind;
 use tokio::signal::unix::signal;
-use tokio::sync::Mutex;
+use tokio::time::interval;
+
+use activity::Activity;
+use changelog::ChangeLogs;
+use core::watcher::{ChangeEvent, HybridWatcher, Watcher as _};
+use link::Links;
+use state::{OpenProject, State};
 
-use core;
-use core::watcher::{AsyncWatcher, Watcher as _};
+/// How often projects that have changed are recorded, a crash has at most this long of changes to
+/// catch up on as well as what's still queued
+const RECORD_EVERY: Duration = Duration::from_secs(30);
 
 pub fn run_client() -> anyhow::Result<()> {
     println!("[client] server started...");
@@ -27,44 +43,106 @@ pub fn run_client() -> anyhow::Result<()> {
     let mut output = rt.block_on(CommandListener::start())?;
     println!("server watcher running");
 
-    let roots: Mutex<HashMap<PathBuf, Project>> = Mutex::new(HashMap::new());
-
     rt.block_on(async {
-        let mut watcher = AsyncWatcher::new().await.unwrap();
+        let mut watcher = HybridWatcher::new();
+        let mut activity = Activity::default();
+        let user = std::env::var("USER").unwrap_or("unknown".to_string());
+        let mut links = Links::new(&format!("{user}-{}", std::process::id()), &user);
+        let mut logs = ChangeLogs::default();
         let mut sigterm = signal(SignalKind::terminate()).expect("Failed to setup signal handler");
+        restore(&mut watcher, &mut links, &mut logs, &mut activity).await;
+        // projects with changes since they were last recorded
+        let mut changed = HashSet::new();
+        let mut record = interval(RECORD_EVERY);
         loop {
             select! {
                 Some(()) = sigterm.recv() => {
                     println!("[client] SIGTERM Received...");
+                    persist(&links, &mut activity).await;
                     break
                 },
                 Some(msg) = watcher.recv() => {
                     println!("watcher: {msg:?}");
+                    changed.insert(msg.root.clone());
+                    local_change(&mut links, &mut logs, &mut activity, &msg).await;
+                },
+                Some(incoming) = links.recv() => {
+                    match links.handle(&mut watcher, incoming).await {
+                        Ok(changes) => for (root, kind) in changes {
+                            changed.insert(root.clone());
+                            if let Err(problem) = logs.record(&root, &kind, Origin::Remote).await {
+                                eprintln!("[client] {problem:?}");
+                                activity.record_error();
+                            }
+                            activity.record_remote(root, kind);
+                        },
+                        Err(problem) => {
+                            eprintln!("[client] {problem:?}");
+                            activity.record_error();
+                        },
+                    }
+                },
+                _ = record.tick() => {
+                    record_changed(&links, &mut changed, &mut activity).await;
                 },
-                Some(msg) = output.next() => {
-                    match msg {
+                Some((command, responder)) = output.next() => {
+                    match command {
                         Command::Open {
                             path
                         } => {
-                            match watcher.watch::<SeaHasher>(&path).await {
+                            let reply = match open_configured(&mut watcher, &mut links, &mut logs, &path).await {
                                 Ok(_) => {
                                     println!("[client] watching path {:?}", &path);
+                                    save(&links, &mut activity).await;
+                                    Reply::Ok
+                                },
+                                Err(problem) => {
+                                    eprintln!("[client] {problem:?}");
+                                    activity.record_error();
+                                    Reply::error(&problem)
                                 },
-                                Err(problem) => eprintln!("[client] {problem:?}"),
                             };
+                            responder.reply(reply);
                         },
                         Command::Close {
                             path
                         } => {
-                            match watcher.unwatch(&path).await {
+                            let reply = match watcher.unwatch(&path).await {
                                 Ok(_) => {
                                     println!("[client] watcher removed {:?}", &path);
+                                    links.close(&path);
+                                    logs.close(&path);
+                                    changed.remove(&path);
+                                    save(&links, &mut activity).await;
+                                    Reply::Ok
                                 },
-                                Err(problem) => eprintln!("[client] {problem:?}"),
-                            }
+                                Err(problem) => {
+                                    eprintln!("[client] {problem:?}");
+                                    activity.record_error();
+                                    Reply::error(&problem)
+                                },
+                            };
+                            responder.reply(reply);
+                        },
+                        Command::Status => {
+                            responder.reply(data(Reply::data(&activity.status(&watcher, &links).await)));
+                        },
+                        Command::List => {
+                            responder.reply(data(Reply::data(&watcher.watched().await)));
+                        },
+                        Command::Stats => {
+                            responder.reply(data(Reply::data(&activity.stats())));
+                        },
+                        Command::Events { limit } => {
+                            responder.reply(data(Reply::data(&activity.events(limit))));
+                        },
+                        Command::Subscribe { projects, paths } => {
+                            responder.subscribe(activity.subscribe(projects, paths));
                         },
                         Command::Shutdown { caller }  => {
                             println!("[client] shutdown request by {caller}");
+                            persist(&links, &mut activity).await;
+                            responder.reply_and_wait(Reply::Ok).await;
                             output.shutdown().await.unwrap();
                             break;
                         }
@@ -77,36 +155,184 @@ pub fn run_client() -> anyhow::Result<()> {
     exit(0);
 }
 
+/// Watches a project, records its files, starts its change log and connects it to its stream. A
+/// path inside a project that's already open is left to that project.
+async fn open(
+    watcher: &mut HybridWatcher,
+    links: &mut Links,
+    logs: &mut ChangeLogs,
+    project: &OpenProject,
+) -> anyhow::Result<()> {
+    let path = project.root.as_path();
+    // the watcher would take over the projects inside it, but they'd keep syncing on their own
+    // links too
+    if let Some(inside) = links
+        .projects()
+        .into_iter()
+        .find(|open| path_is_parent(path, &open.root))
+    {
+        return Err(anyhow::anyhow!(
+            "{:?} is open inside {path:?}, close it first",
+            inside.root
+        ));
+    }
+    watcher.watch::<SeaHasher>(path).await?;
+    if !watcher
+        .watched()
+        .await
+        .iter()
+        .any(|watched| watched.root == path)
+    {
+        return Ok(());
+    }
+    let linked = match state::record(path).await {
+        Ok(()) => links.open(project),
+        Err(problem) => Err(problem),
+    };
+    if let Err(problem) = linked {
+        watcher.unwatch(path).await?;
+        return Err(problem);
+    }
+    logs.open(path).await;
+    Ok(())
+}
+
+/// Opens a project with the sync settings in its config, for the open command.
+async fn open_configured(
+    watcher: &mut HybridWatcher,
+    links: &mut Links,
+    logs: &mut ChangeLogs,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let project = OpenProject {
+        root: path.to_path_buf(),
+        sync: ProjectConfig::load(path).await?.sync,
+        seen: None,
+    };
+    open(watcher, links, logs, &project).await
+}
+
+/// Opens the projects the last daemon had open and sends what changed in them while no one was
+/// watching, what changed on their streams arrives once they're connected.
+async fn restore(
+    watcher: &mut HybridWatcher,
+    links: &mut Links,
+    logs: &mut ChangeLogs,
+    activity: &mut Activity,
+) {
+    let state = match State::load().await {
+        Ok(state) => state,
+        Err(problem) => {
+            eprintln!("[client] couldn't load the projects to reopen: {problem:?}");
+            activity.record_error();
+            return;
+        }
+    };
+    for project in state.projects {
+        // the changes have to be found before opening records the project afresh
+        let reopened = match state::catch_up(&project.root).await {
+            Ok(changes) => open(watcher, links, logs, &project).await.map(|_| changes),
+            Err(problem) => Err(problem),
+        };
+        let changes = match reopened {
+            Ok(changes) => changes,
+            Err(problem) => {
+                eprintln!("[client] couldn't reopen {:?}: {problem:?}", project.root);
+                activity.record_error();
+                continue;
+            }
+        };
+        println!(
+            "[client] reopened {:?}, {} changes to catch up on",
+            project.root,
+            changes.len()
+        );
+        for event in changes {
+            local_change(links, logs, activity, &event).await;
+        }
+    }
+    save(links, activity).await;
+}
+
+/// A change made on this machine is logged and queued for the server
+async fn local_change(
+    links: &mut Links,
+    logs: &mut ChangeLogs,
+    activity: &mut Activity,
+    event: &ChangeEvent,
+) {
+    activity.record(event);
+    if let Err(problem) = logs.record(&event.root, &event.kind, Origin::Local).await {
+        eprintln!("[client] {problem:?}");
+        activity.record_error();
+    }
+    if let Err(problem) = links.send(event).await {
+        eprintln!("[client] {problem:?}");
+        activity.record_error();
+    }
+}
+
+/// Writes down the open projects for the next daemon to reopen
+async fn save(links: &Links, activity: &mut Activity) {
+    let state = State {
+        projects: links.projects(),
+    };
+    if let Err(problem) = state.save().await {
+        eprintln!("[client] couldn't save the open projects: {problem:?}");
+        activity.record_error();
+    }
+}
+
+/// Records every open project's files and how far through its stream it got, for the next daemon
+/// to catch up from.
+async fn persist(links: &Links, activity: &mut Activity) {
+    for project in links.projects() {
+        if let Err(problem) = state::record(&project.root).await {
+            eprintln!("[client] couldn't record {:?}: {problem:?}", project.root);
+            activity.record_error();
+        }
+    }
+    save(links, activity).await;
+}
+
+/// Records the projects that changed since they last were. Changes the server hasn't got yet are
+/// kept in the queue, a crash only has what happened since to catch up on.
+async fn record_changed(links: &Links, changed: &mut HashSet<PathBuf>, activity: &mut Activity) {
+    if changed.is_empty() {
+        return;
+    }
+    for root in changed.drain() {
+        if let Err(problem) = state::record(&root).await {
+            eprintln!("[client] couldn't record {root:?}: {problem:?}");
+            activity.record_error();
+        }
+    }
+    save(links, activity).await;
+}
+
+/// Replies with data, or why it couldn't be put together
+fn data(reply: anyhow::Result<Reply>) -> Reply {
+    reply.unwrap_or_else(|err| Reply::error(&err))
+}
+
 // leaving user to remind me to daemonize
 pub fn start_deamon(user: &str) -> anyhow::Result<()> {
     if is_daemon_running() {
         return Err(anyhow::anyhow!("Daemon is already running"));
     }
-    let package_name = "sink";
-    let mut tmp_directory = std::env::temp_dir();
-    tmp_directory.push(package_name);
-    match create_dir_all(&tmp_directory) {
-        Ok(_) => {}
-        Err(_) => {}
-    }
-    let mut stdout_path = tmp_directory.clone();
-    stdout_path.set_file_name(package_name);
-    stdout_path.set_extension("out");
-    let stdout = File::create(&stdout_path)?;
-    let mut stderr_path = tmp_directory.clone();
-    stderr_path.set_file_name(package_name);
-    stderr_path.set_extension("err");
-    let stderr = File::create(&stderr_path)?;
+    let runtime_dir = core::runtime_dir()?;
+    let stdout = File::create(runtime_dir.join("sink.out"))?;
+    let stderr = File::create(runtime_dir.join("sink.err"))?;
 
-    let pid_path = core::pid_path();
+    let pid_path = core::pid_path()?;
     let daemonize = Daemonize::new()
         .pid_file(pid_path) // Every method except `new` and `start`
         .chown_pid_file(false) // is optional, see `Daemonize` documentation
-        .working_directory(tmp_directory) // for default behaviour.
+        .working_directory(runtime_dir) // for default behaviour.
         .user(user)
         .umask(0o027) // Set umask, `0o027` by default.
-        .stdout(stdout) // Redirect stdout to `/tmp/daemon.out`.
-        .stderr(stderr); // Redirect stderr to `/tmp/daemon.err`.
+        .stdout(stdout) // Redirect stdout to `sink.out` in the runtime dir.
+        .stderr(stderr); // Redirect stderr to `sink.err` in the runtime dir.
     match daemonize.execute() {
         daemonize::Outcome::Child(_) => run_client()?,
         daemonize::Outcome::Parent(outcome) => outcome.map(|_| ())?,
@@ -0,0 +1,983 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hasher, RandomState},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::*;
+use core::{
+    messages::{
+        self, ClientMessage, Envelope, ErrorCode, Hello, HelloReply, Response, ServerMessage,
+        ToClient,
+    },
+    patch,
+    status::ConnectionState,
+    watcher::{ChangeEvent, ChangeKind, Watcher},
+};
+use fastwebsockets::{FragmentCollectorRead, Frame, OpCode, Payload, WebSocket, handshake};
+use http_body_util::Empty;
+use hyper::{Request, Uri, body::Bytes, header, upgrade::Upgraded};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::{
+    io::AsyncRead,
+    net::TcpStream,
+    select,
+    sync::{Notify, mpsc},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+
+use crate::queue::{Entry, Queue};
+use crate::remote::Remote;
+use crate::state::OpenProject;
+
+/// How many changes from the server can wait for the daemon to write them
+const INCOMING: usize = 1000;
+
+/// Shortest wait before reconnecting, it doubles with every failed attempt
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How long connecting to the server can take before it's given up on
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the server's pinged when nothing else is going on
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// How long the server can go without saying anything, pongs included, before it's presumed gone
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How a project's link to the server is doing
+#[derive(Debug, Clone, Default)]
+pub struct LinkStatus {
+    pub connection: ConnectionState,
+    /// Changes the server hasn't acknowledged yet, sent or not
+    pub pending: usize,
+    /// When the server last acknowledged one of our changes
+    pub last_sync: Option<SystemTime>,
+    /// Why the last connection ended
+    pub last_error: Option<String>,
+    /// When the next attempt to connect is made, while disconnected
+    pub retry_at: Option<SystemTime>,
+}
+
+/// A change another participant made, for the project at root
+pub struct Incoming {
+    root: PathBuf,
+    message: ClientMessage,
+}
+
+struct Link {
+    project: OpenProject,
+    /// The connection's [`Connection::seen`]
+    seen: Arc<AtomicU64>,
+    /// The connection's [`Connection::resyncing`]
+    resyncing: Arc<Mutex<Option<u64>>>,
+    queue: Arc<Mutex<Queue>>,
+    /// Tells the connection there's something new in the queue
+    wake: Arc<Notify>,
+    status: Arc<Mutex<LinkStatus>>,
+    remote: Remote,
+    /// The connection's [`Connection::synced`]
+    synced: Arc<Mutex<HashMap<PathBuf, String>>>,
+    task: JoinHandle<()>,
+}
+
+impl Link {
+    /// Brings the project in line with the server's copy, files the server doesn't have are
+    /// queued for it.
+    async fn reconcile<W: Watcher + Send>(
+        &mut self,
+        watcher: &mut W,
+        files: HashMap<PathBuf, String>,
+    ) -> Result<Vec<ChangeKind>> {
+        let pending = self.queue.lock().unwrap().paths();
+        let (changes, missing) = self.remote.reconcile(watcher, &files, &pending).await?;
+        *self.synced.lock().unwrap() = files;
+        for path in missing {
+            let created = ChangeKind::Created(path);
+            let messages = match messages_for(&self.project.root, &created).await {
+                Result::Ok(messages) => messages,
+                // like anything else that isn't text, it's not synced
+                Err(err) => {
+                    eprintln!("[client] can't send {created:?}: {err}");
+                    continue;
+                }
+            };
+            let mut queue = self.queue.lock().unwrap();
+            for message in messages {
+                queue.push(message)?;
+            }
+        }
+        self.wake.notify_one();
+        Ok(changes)
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Every open project's websocket to its stream. Local changes are sent through here and what
+/// everyone else on the stream changes comes back out of [`Links::recv`].
+pub struct Links {
+    client_id: String,
+    user: String,
+    links: HashMap<PathBuf, Link>,
+    sender: mpsc::Sender<Incoming>,
+    incoming: mpsc::Receiver<Incoming>,
+}
+
+impl Links {
+    pub fn new(client_id: &str, user: &str) -> Self {
+        let (sender, incoming) = mpsc::channel(INCOMING);
+        Self {
+            client_id: client_id.to_string(),
+            user: user.to_string(),
+            links: HashMap::new(),
+            sender,
+            incoming,
+        }
+    }
+
+    /// Connects the project to its server, carrying on from the last change it saw. The
+    /// connection's made in the background.
+    pub fn open(&mut self, project: &OpenProject) -> Result<()> {
+        let root = project.root.as_path();
+        let name = root
+            .file_name()
+            .ok_or(anyhow!("{root:?} has no name to share it by"))?;
+        let stream = project
+            .sync
+            .stream
+            .clone()
+            .unwrap_or_else(|| name.to_string_lossy().to_string());
+        let link = Connection {
+            server: project.sync.server.clone(),
+            hello: Hello::new(&self.client_id, &self.user, &stream),
+            seen: Arc::new(AtomicU64::new(project.seen.unwrap_or_default())),
+            resyncing: Arc::default(),
+            resume: project.seen.is_some(),
+            root: root.to_path_buf(),
+            incoming: self.sender.clone(),
+            queue: Arc::new(Mutex::new(Queue::open(root)?)),
+            wake: Arc::default(),
+            status: Arc::default(),
+            synced: Arc::default(),
+        };
+        let (queue, wake, status) = (link.queue.clone(), link.wake.clone(), link.status.clone());
+        let synced = link.synced.clone();
+        let (seen, resyncing) = (link.seen.clone(), link.resyncing.clone());
+        let task = tokio::spawn(link.run());
+        self.links.insert(
+            root.to_path_buf(),
+            Link {
+                project: project.clone(),
+                seen,
+                resyncing,
+                queue,
+                wake,
+                status,
+                remote: Remote::new(root),
+                synced,
+                task,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn close(&mut self, root: &Path) {
+        self.links.remove(root);
+    }
+
+    /// Every open project with the last change it's seen from its stream
+    pub fn projects(&self) -> Vec<OpenProject> {
+        self.links
+            .values()
+            .map(|link| OpenProject {
+                seen: Some(link.seen.load(Ordering::Relaxed)),
+                ..link.project.clone()
+            })
+            .collect()
+    }
+
+    pub fn status(&self, root: &Path) -> LinkStatus {
+        self.links
+            .get(root)
+            .map(|link| LinkStatus {
+                pending: link.queue.lock().unwrap().len(),
+                ..link.status.lock().unwrap().clone()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Queues a local change for the server with the file as it is now, it's sent whenever
+    /// there's a connection.
+    pub async fn send(&mut self, event: &ChangeEvent) -> Result<()> {
+        let Some(link) = self.links.get_mut(&event.root) else {
+            return Ok(());
+        };
+        let messages = messages_for(&event.root, &event.kind).await?;
+        let mut queue = link.queue.lock().unwrap();
+        for message in messages {
+            queue.push(message)?;
+        }
+        link.wake.notify_one();
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Option<Incoming> {
+        self.incoming.recv().await
+    }
+
+    /// Writes a change from the server into its project, returning the root and the changes
+    /// made.
+    pub async fn handle<W: Watcher + Send>(
+        &mut self,
+        watcher: &mut W,
+        incoming: Incoming,
+    ) -> Result<Vec<(PathBuf, ChangeKind)>> {
+        // the project could have been closed while the change was on its way
+        let Some(link) = self.links.get_mut(&incoming.root) else {
+            return Ok(Vec::new());
+        };
+        if let ClientMessage::Snapshot { files } = incoming.message {
+            let changes = link.reconcile(watcher, files).await?;
+            // only now it's reconciled is what was missed no longer needed. The server could have
+            // started over, seen goes back to wherever its stream is up to.
+            if let Some(seq) = link.resyncing.lock().unwrap().take() {
+                link.seen.store(seq, Ordering::Relaxed);
+            }
+            let root = &incoming.root;
+            return Ok(changes
+                .into_iter()
+                .map(|kind| (root.clone(), kind))
+                .collect());
+        }
+        // the server's copy is what came in, whether or not it can be written here
+        {
+            let mut synced = link.synced.lock().unwrap();
+            match &incoming.message {
+                ClientMessage::Create { path, content } => {
+                    let content = content.clone().unwrap_or_default();
+                    synced.insert(path.clone(), content);
+                }
+                ClientMessage::Modify { path, content, .. } => {
+                    synced.insert(path.clone(), content.clone());
+                }
+                ClientMessage::Delete { path, .. } => {
+                    synced.remove(path);
+                }
+                ClientMessage::Project { .. }
+                | ClientMessage::Resync { .. }
+                | ClientMessage::Snapshot { .. } => {}
+            }
+        }
+        let change = link.remote.handle(watcher, incoming.message).await?;
+        Ok(change.into_iter().collect())
+    }
+}
+
+/// What to tell the server about a change, files are read as they are now.
+async fn messages_for(root: &Path, kind: &ChangeKind) -> Result<Vec<ServerMessage>> {
+    let read = |path: &Path| tokio::fs::read_to_string(root.join(path));
+    Ok(match kind {
+        ChangeKind::Created(path) => vec![ServerMessage::Create {
+            path: path.clone(),
+            content: Some(read(path).await?),
+        }],
+        ChangeKind::Modified(path) => vec![ServerMessage::Modify {
+            path: path.clone(),
+            content: read(path).await?,
+        }],
+        ChangeKind::Deleted(path) => vec![ServerMessage::Delete { path: path.clone() }],
+        ChangeKind::Renamed { from, to } => vec![
+            ServerMessage::Delete { path: from.clone() },
+            ServerMessage::Create {
+                path: to.clone(),
+                content: Some(read(to).await?),
+            },
+        ],
+    })
+}
+
+/// Swaps a Modify of a file the server's copy of is known for a Patch of only the lines that
+/// changed, and keeps track of where the server's copy ends up. If it's not where we thought the
+/// server answers with a Conflict and the whole file goes instead.
+fn patch_known(synced: &mut HashMap<PathBuf, String>, message: ServerMessage) -> ServerMessage {
+    match message {
+        ServerMessage::Modify { path, content } => {
+            let message = match synced.get(&path) {
+                Some(old) => ServerMessage::Patch {
+                    path: path.clone(),
+                    base: patch::version(old),
+                    hunks: patch::hunks(old, &content),
+                },
+                None => ServerMessage::Modify {
+                    path: path.clone(),
+                    content: content.clone(),
+                },
+            };
+            synced.insert(path, content);
+            message
+        }
+        ServerMessage::Create { path, content } => {
+            synced.insert(path.clone(), content.clone().unwrap_or_default());
+            ServerMessage::Create { path, content }
+        }
+        ServerMessage::Delete { path } => {
+            synced.remove(&path);
+            ServerMessage::Delete { path }
+        }
+        message => message,
+    }
+}
+
+/// Frames waiting to be written to the websocket, pongs and closes included.
+type Outgoing = mpsc::Sender<Frame<'static>>;
+
+async fn send<T: Serialize>(outgoing: &Outgoing, id: u64, body: &T) -> Result<()> {
+    let payload = messages::encode(id, body)?;
+    outgoing
+        .send(Frame::binary(Payload::Owned(payload)))
+        .await
+        .map_err(|_| anyhow!("connection closed"))
+}
+
+/// Exponential backoff between attempts to connect. Each wait is somewhere between half and all
+/// of the current ceiling so clients that lost the server together don't all come back at once.
+#[derive(Default)]
+struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    fn next(&mut self) -> Duration {
+        let ceiling = MIN_BACKOFF
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(MAX_BACKOFF);
+        self.attempts += 1;
+        // a fresh RandomState is randomly keyed, plenty for spreading out reconnects
+        let random = RandomState::new().build_hasher().finish();
+        let half = ceiling / 2;
+        half + Duration::from_millis(random % (half.as_millis() as u64 + 1))
+    }
+
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// A project's connection to its stream, lives in its own task. It's remade whenever it drops
+/// for as long as the project's open.
+struct Connection {
+    server: String,
+    hello: Hello,
+    /// The sequence number of the last change from the stream, a new connection resumes from it.
+    /// 0 before there's been one.
+    seen: Arc<AtomicU64>,
+    /// Where the stream's up to while the project's being reconciled after a resync, seen isn't
+    /// moved on until it has been so a restart part way through resyncs again
+    resyncing: Arc<Mutex<Option<u64>>>,
+    /// Whether to resume from seen, set once the project's been on the stream even if nothing's
+    /// come from it
+    resume: bool,
+    root: PathBuf,
+    incoming: mpsc::Sender<Incoming>,
+    queue: Arc<Mutex<Queue>>,
+    wake: Arc<Notify>,
+    status: Arc<Mutex<LinkStatus>>,
+    /// Text files as the server has them as far as we know, from what we've sent and what's come
+    /// back from the stream. Edits to these go out as patches against that copy.
+    synced: Arc<Mutex<HashMap<PathBuf, String>>>,
+}
+
+impl Connection {
+    async fn run(mut self) {
+        let mut backoff = Backoff::default();
+        loop {
+            self.status.lock().unwrap().connection = ConnectionState::Connecting;
+            let seen = self.seen.load(Ordering::Relaxed);
+            self.hello.resume_from = (self.resume || seen > 0).then_some(seen);
+            let error = match self.sync().await {
+                Result::Ok(()) => "the server closed the connection".to_string(),
+                Err(err) => format!("{err:#}"),
+            };
+            let delay = {
+                let mut status = self.status.lock().unwrap();
+                // only a connection that got going earns a quick retry
+                if status.connection == ConnectionState::Connected {
+                    backoff.reset();
+                    self.resume = true;
+                }
+                let delay = backoff.next();
+                eprintln!(
+                    "[client] {:?} lost the server, retrying in {delay:?}: {error}",
+                    self.root
+                );
+                status.connection = ConnectionState::Disconnected;
+                status.last_error = Some(error);
+                status.retry_at = Some(SystemTime::now() + delay);
+                delay
+            };
+            sleep(delay).await;
+        }
+    }
+
+    async fn sync(&self) -> Result<()> {
+        let ws = timeout(CONNECT_TIMEOUT, connect(&self.server))
+            .await
+            .map_err(|_| anyhow!("connecting took longer than {CONNECT_TIMEOUT:?}"))??;
+        let (read, mut write) = ws.split(tokio::io::split);
+        let mut ws = FragmentCollectorRead::new(read);
+        let (outgoing, mut frames) = mpsc::channel::<Frame<'static>>(100);
+        // the hello has to go first and the project before any changes, these are queued up
+        // before anything else gets the chance
+        send(&outgoing, 0, &self.hello).await?;
+        // the project's kept on the server under the stream's name, so everyone on the stream
+        // shares one copy whatever theirs is called locally
+        let project = ServerMessage::Project {
+            root: PathBuf::from(&self.hello.stream),
+        };
+        send(&outgoing, 0, &project).await?;
+        // whatever went unanswered on the last connection goes again
+        self.queue.lock().unwrap().restart();
+        let heard = Mutex::new(Instant::now());
+        let writing = async {
+            while let Some(frame) = frames.recv().await {
+                write.write_frame(frame).await?;
+            }
+            Ok(())
+        };
+        let reading = async {
+            let mut send_fn = |frame| {
+                let outgoing = outgoing.clone();
+                async move {
+                    outgoing
+                        .send(frame)
+                        .await
+                        .map_err(|_| anyhow!("connection closed"))
+                }
+            };
+            let frame = ws.read_frame(&mut send_fn).await?;
+            if let HelloReply::Refused { reason } =
+                messages::decode::<HelloReply>(&frame.payload)?.body
+            {
+                return Err(anyhow!("the server refused us: {reason}"));
+            }
+            {
+                let mut status = self.status.lock().unwrap();
+                status.connection = ConnectionState::Connected;
+                status.retry_at = None;
+            }
+            self.read_changes(&mut ws, &mut send_fn, &outgoing, &heard)
+                .await
+        };
+        let heartbeat = async {
+            loop {
+                sleep(HEARTBEAT).await;
+                if heard.lock().unwrap().elapsed() > HEARTBEAT_TIMEOUT {
+                    return Err(anyhow!(
+                        "the server hasn't answered in {HEARTBEAT_TIMEOUT:?}"
+                    ));
+                }
+                let ping = Frame::new(true, OpCode::Ping, None, Payload::Owned(Vec::new()));
+                outgoing
+                    .send(ping)
+                    .await
+                    .map_err(|_| anyhow!("connection closed"))?;
+            }
+        };
+        let sending = async {
+            loop {
+                let next = self.next_unsent();
+                match next {
+                    Some(entry) => send(&outgoing, entry.seq, &entry.message).await?,
+                    None => self.wake.notified().await,
+                }
+            }
+        };
+        select! {
+            result = writing => result,
+            result = reading => result,
+            result = sending => result,
+            result = heartbeat => result,
+        }
+    }
+
+    /// Hands changes from the rest of the stream to the daemon and keeps track of what the
+    /// server's acknowledged, until the server hangs up.
+    async fn read_changes<S, F, R>(
+        &self,
+        ws: &mut FragmentCollectorRead<S>,
+        send_fn: &mut F,
+        outgoing: &Outgoing,
+        heard: &Mutex<Instant>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+        F: FnMut(Frame<'static>) -> R,
+        R: Future<Output = Result<()>>,
+    {
+        loop {
+            let frame = ws.read_frame(send_fn).await?;
+            *heard.lock().unwrap() = Instant::now();
+            match frame.opcode {
+                OpCode::Close => return Ok(()),
+                OpCode::Text | OpCode::Binary => {}
+                _ => continue,
+            }
+            let Envelope { id, body } = messages::decode::<ToClient>(&frame.payload)?;
+            let response = match body {
+                ToClient::Change(message) => {
+                    // a change's id is its place in the stream, resyncing carries on from seq
+                    match &message {
+                        ClientMessage::Resync { seq } => {
+                            *self.resyncing.lock().unwrap() = Some(*seq);
+                            send(outgoing, 0, &ServerMessage::Snapshot).await?;
+                            continue;
+                        }
+                        ClientMessage::Project { .. } | ClientMessage::Snapshot { .. } => {}
+                        _ => match &mut *self.resyncing.lock().unwrap() {
+                            Some(seq) => *seq = id.max(*seq),
+                            None => self.seen.store(id, Ordering::Relaxed),
+                        },
+                    }
+                    let root = self.root.clone();
+                    self.incoming
+                        .send(Incoming { root, message })
+                        .await
+                        .map_err(|_| anyhow!("the daemon stopped listening"))?;
+                    continue;
+                }
+                ToClient::Response(response) => response,
+            };
+            match response {
+                Response::Ack { id, seq } => {
+                    self.queue.lock().unwrap().done(id)?;
+                    // our own changes are in the stream too, resuming shouldn't hand them back
+                    match (seq, &mut *self.resyncing.lock().unwrap()) {
+                        (Some(seq), Some(resync)) => *resync = seq.max(*resync),
+                        (Some(seq), None) => {
+                            self.seen.fetch_max(seq, Ordering::Relaxed);
+                        }
+                        (None, _) => {}
+                    }
+                    self.status.lock().unwrap().last_sync = Some(SystemTime::now());
+                }
+                // everything after it is sent again on the next connection too, so the server
+                // still hears the changes in the order they were made
+                Response::Rejected {
+                    id,
+                    code: ErrorCode::Internal,
+                    message,
+                } => {
+                    return Err(anyhow!(
+                        "the server failed on {id}, sending it again: {message}"
+                    ));
+                }
+                Response::Rejected {
+                    id,
+                    code: ErrorCode::NotFound,
+                    message,
+                } if let Some(path) = self.edited(id) => {
+                    eprintln!(
+                        "[client] the server doesn't have {path:?}, sending all of it: {message}"
+                    );
+                    self.resend_whole(id, path, true).await?;
+                }
+                // it'll never be taken, there's no point sending it again
+                Response::Rejected { id, code, message } => {
+                    eprintln!("[client] server rejected {id} ({code:?}): {message}");
+                    self.queue.lock().unwrap().done(id)?;
+                }
+                Response::Conflict { id, path, .. } => {
+                    eprintln!(
+                        "[client] {path:?} conflicts with the server's copy, sending all of it"
+                    );
+                    self.resend_whole(id, path, false).await?;
+                }
+            }
+        }
+    }
+
+    /// The oldest change that hasn't gone out on this connection yet. It's only made a patch now,
+    /// so edits queued while it waited are folded into one.
+    fn next_unsent(&self) -> Option<Entry> {
+        let mut entry = self.queue.lock().unwrap().next_unsent()?;
+        entry.message = patch_known(&mut self.synced.lock().unwrap(), entry.message);
+        Some(entry)
+    }
+
+    /// The file a queued change edits, when it's an edit
+    fn edited(&self, id: u64) -> Option<PathBuf> {
+        match &self.queue.lock().unwrap().get(id)?.message {
+            ServerMessage::Modify { path, .. } | ServerMessage::Patch { path, .. } => {
+                Some(path.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Swaps a change the server couldn't apply to its copy for the whole file as it is now, as a
+    /// Create when the server doesn't have it at all. Nothing's sent when the file's gone, its
+    /// delete is on the way.
+    async fn resend_whole(&self, id: u64, path: PathBuf, missing: bool) -> Result<()> {
+        let content = match tokio::fs::read_to_string(self.root.join(&path)).await {
+            Result::Ok(content) => Some(content),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        // whatever we thought the server has is wrong
+        self.synced.lock().unwrap().remove(&path);
+        let mut queue = self.queue.lock().unwrap();
+        queue.done(id)?;
+        if let Some(content) = content {
+            let message = if missing {
+                let content = Some(content);
+                ServerMessage::Create { path, content }
+            } else {
+                ServerMessage::Modify { path, content }
+            };
+            queue.push(message)?;
+            self.wake.notify_one();
+        }
+        Ok(())
+    }
+}
+
+/// Hands hyper's connection task to tokio
+struct SpawnExecutor;
+
+impl<F> hyper::rt::Executor<F> for SpawnExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        tokio::spawn(fut);
+    }
+}
+
+async fn connect(server: &str) -> Result<WebSocket<TokioIo<Upgraded>>> {
+    let uri = server.parse::<Uri>()?;
+    if uri.scheme_str() != Some("ws") {
+        return Err(anyhow!(
+            "{server} isn't a ws:// address, tls isn't supported yet"
+        ));
+    }
+    let authority = uri
+        .authority()
+        .ok_or(anyhow!("{server} doesn't say which host"))?;
+    let stream = TcpStream::connect((authority.host(), authority.port_u16().unwrap_or(80))).await?;
+    let request = Request::builder()
+        .method("GET")
+        .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(header::HOST, authority.as_str())
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "upgrade")
+        .header(header::SEC_WEBSOCKET_KEY, handshake::generate_key())
+        .header(header::SEC_WEBSOCKET_VERSION, "13")
+        .body(Empty::<Bytes>::new())?;
+    let (ws, _) = handshake::client(&SpawnExecutor, request, stream).await?;
+    Ok(ws)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::config::SyncConfig;
+    use core::testing::{TempDir, block_on};
+    use core::watcher::MockWatcher;
+    use fastwebsockets::Role;
+
+    /// Opens the project somewhere nothing's listening, the link's own connection never gets
+    /// anywhere
+    fn open(links: &mut Links, root: &Path, seen: u64) -> OpenProject {
+        let project = OpenProject {
+            root: root.to_path_buf(),
+            sync: SyncConfig {
+                server: "ws://127.0.0.1:1/ws".to_string(),
+                stream: Some("stream".to_string()),
+            },
+            seen: Some(seen),
+        };
+        links.open(&project).unwrap();
+        project
+    }
+
+    /// A connection sharing an open link's state, to drive by hand
+    fn connection(links: &Links, root: &Path) -> Connection {
+        let link = &links.links[root];
+        Connection {
+            server: link.project.sync.server.clone(),
+            hello: Hello::new("client", "user", "stream"),
+            seen: link.seen.clone(),
+            resyncing: link.resyncing.clone(),
+            resume: true,
+            root: root.to_path_buf(),
+            incoming: links.sender.clone(),
+            queue: link.queue.clone(),
+            wake: link.wake.clone(),
+            status: link.status.clone(),
+            synced: link.synced.clone(),
+        }
+    }
+
+    /// Has the connection read what the server says until it hangs up, returning what it sent
+    /// back
+    async fn hear<const N: usize>(
+        connection: &Connection,
+        messages: [ToClient; N],
+    ) -> Vec<ServerMessage> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = WebSocket::after_handshake(server, Role::Server);
+        for message in messages {
+            let payload = messages::encode(0, &message).unwrap();
+            let frame = Frame::binary(Payload::Owned(payload));
+            server.write_frame(frame).await.unwrap();
+        }
+        server.write_frame(Frame::close(1000, b"")).await.unwrap();
+        let (read, _write) =
+            WebSocket::after_handshake(client, Role::Client).split(tokio::io::split);
+        let mut ws = FragmentCollectorRead::new(read);
+        let (outgoing, mut frames) = mpsc::channel(10);
+        let mut send_fn = |frame| {
+            let outgoing = outgoing.clone();
+            async move { outgoing.send(frame).await.map_err(|_| anyhow!("closed")) }
+        };
+        let heard = Mutex::new(Instant::now());
+        connection
+            .read_changes(&mut ws, &mut send_fn, &outgoing, &heard)
+            .await
+            .unwrap();
+        drop(outgoing);
+        let mut sent = Vec::new();
+        while let Some(frame) = frames.recv().await {
+            // the close is answered too
+            if frame.opcode != OpCode::Binary {
+                continue;
+            }
+            sent.push(
+                messages::decode::<ServerMessage>(&frame.payload)
+                    .unwrap()
+                    .body,
+            );
+        }
+        sent
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_limit_with_jitter() {
+        let mut backoff = Backoff::default();
+        let waits = (0..10).map(|_| backoff.next()).collect::<Vec<_>>();
+        assert!(waits[0] >= MIN_BACKOFF / 2 && waits[0] <= MIN_BACKOFF);
+        assert!(waits[3] >= MIN_BACKOFF * 4 && waits[3] <= MIN_BACKOFF * 8);
+        assert!(waits[9] >= MAX_BACKOFF / 2 && waits[9] <= MAX_BACKOFF);
+
+        backoff.reset();
+        assert!(backoff.next() <= MIN_BACKOFF);
+    }
+
+    #[test]
+    fn test_edits_to_files_the_server_has_are_patches() {
+        let mut synced = HashMap::new();
+        let modify = |content: &str| ServerMessage::Modify {
+            path: "file.txt".into(),
+            content: content.to_string(),
+        };
+        // nothing's known about the server's copy yet
+        assert_eq!(
+            patch_known(&mut synced, modify("one\ntwo\n")),
+            modify("one\ntwo\n")
+        );
+        let patched = patch_known(&mut synced, modify("one\n2\n"));
+        let ServerMessage::Patch { base, hunks, .. } = patched else {
+            panic!("expected a patch, got {patched:?}");
+        };
+        assert_eq!(
+            patch::apply("one\ntwo\n", base, &hunks).unwrap(),
+            "one\n2\n"
+        );
+
+        let delete = ServerMessage::Delete {
+            path: "file.txt".into(),
+        };
+        patch_known(&mut synced, delete);
+        assert_eq!(
+            patch_known(&mut synced, modify("three\n")),
+            modify("three\n")
+        );
+    }
+
+    #[test]
+    fn test_edits_waiting_to_go_out_are_sent_as_one_patch() {
+        let root = TempDir::new("patch-at-send");
+        std::fs::write(root.join("file.txt"), "one\ntwo\n").unwrap();
+
+        block_on(async {
+            let mut links = Links::new("client", "user");
+            open(&mut links, &root, 0);
+            let connection = connection(&links, &root);
+            let created = ChangeEvent::new(&root, ChangeKind::Created("file.txt".into()), None);
+            links.send(&created).await.unwrap();
+            assert!(matches!(
+                connection.next_unsent().unwrap().message,
+                ServerMessage::Create { .. }
+            ));
+
+            let modified = ChangeEvent::new(&root, ChangeKind::Modified("file.txt".into()), None);
+            for content in ["one\n2\n", "one\n2\nthree\n"] {
+                std::fs::write(root.join("file.txt"), content).unwrap();
+                links.send(&modified).await.unwrap();
+            }
+            assert_eq!(connection.queue.lock().unwrap().len(), 2);
+
+            let message = connection.next_unsent().unwrap().message;
+            let ServerMessage::Patch { base, hunks, .. } = message else {
+                panic!("expected a patch, got {message:?}");
+            };
+            assert_eq!(
+                patch::apply("one\ntwo\n", base, &hunks).unwrap(),
+                "one\n2\nthree\n"
+            );
+            assert!(connection.next_unsent().is_none());
+        });
+    }
+
+    #[test]
+    fn test_a_patch_to_a_file_the_server_lost_is_sent_whole() {
+        let root = TempDir::new("patch-not-found");
+        std::fs::write(root.join("file.txt"), "one\n2\n").unwrap();
+
+        block_on(async {
+            let mut links = Links::new("client", "user");
+            open(&mut links, &root, 0);
+            let connection = connection(&links, &root);
+            let known = ("file.txt".into(), "one\ntwo\n".to_string());
+            connection.synced.lock().unwrap().extend([known]);
+            let modified = ChangeEvent::new(&root, ChangeKind::Modified("file.txt".into()), None);
+            links.send(&modified).await.unwrap();
+            let patch = connection.next_unsent().unwrap();
+            assert!(matches!(patch.message, ServerMessage::Patch { .. }));
+
+            let rejected = Response::Rejected {
+                id: patch.seq,
+                code: ErrorCode::NotFound,
+                message: "file not found".to_string(),
+            };
+            hear(&connection, [ToClient::Response(rejected)]).await;
+            assert_eq!(
+                connection.next_unsent().unwrap().message,
+                ServerMessage::Create {
+                    path: "file.txt".into(),
+                    content: Some("one\n2\n".to_string()),
+                }
+            );
+            assert_eq!(connection.queue.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_a_resync_reconciles_the_project_with_the_servers_copy() {
+        let root = TempDir::new("resync");
+        std::fs::write(root.join("stale.txt"), "old\n").unwrap();
+        std::fs::write(root.join("edited.txt"), "mine\n").unwrap();
+        std::fs::write(root.join("only-mine.txt"), "mine\n").unwrap();
+        let files = HashMap::from([
+            ("stale.txt".into(), "new\n".to_string()),
+            ("edited.txt".into(), "theirs\n".to_string()),
+            ("only-theirs.txt".into(), "theirs\n".to_string()),
+        ]);
+
+        block_on(async {
+            let mut links = Links::new("client", "user");
+            // the server's been restarted since, its stream starts over below this
+            let project = open(&mut links, &root, 500);
+            let edited = ServerMessage::Modify {
+                path: "edited.txt".into(),
+                content: "mine\n".to_string(),
+            };
+            let connection = connection(&links, &root);
+            connection.queue.lock().unwrap().push(edited).unwrap();
+
+            // the server lost the log, asks for the snapshot and acks something sent before it
+            // gets it, then hangs up
+            let ack = Response::Ack {
+                id: 99,
+                seq: Some(11),
+            };
+            let sent = hear(
+                &connection,
+                [
+                    ToClient::Change(ClientMessage::Resync { seq: 9 }),
+                    ToClient::Response(ack),
+                    ToClient::Change(ClientMessage::Snapshot {
+                        files: files.clone(),
+                    }),
+                ],
+            )
+            .await;
+            assert_eq!(sent, vec![ServerMessage::Snapshot]);
+            assert_eq!(connection.seen.load(Ordering::Relaxed), 500);
+
+            let (mut watcher, _handle) = MockWatcher::new();
+            // the resync itself never reaches the daemon, only the snapshot
+            let incoming = links.recv().await.unwrap();
+            let mut changes = links.handle(&mut watcher, incoming).await.unwrap();
+            changes.sort_by_key(|(_, kind)| kind.paths()[0].to_path_buf());
+            assert_eq!(
+                changes,
+                vec![
+                    (
+                        project.root.clone(),
+                        ChangeKind::Created("only-theirs.txt".into())
+                    ),
+                    (
+                        project.root.clone(),
+                        ChangeKind::Modified("stale.txt".into())
+                    ),
+                ]
+            );
+            // seen only moves once the project's caught up, to where the new stream is
+            assert_eq!(connection.seen.load(Ordering::Relaxed), 11);
+            let queued = std::iter::from_fn(|| connection.next_unsent())
+                .map(|entry| entry.message.path().unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                queued,
+                vec![PathBuf::from("edited.txt"), PathBuf::from("only-mine.txt")]
+            );
+        });
+        let read = |path: &str| std::fs::read_to_string(root.join(path)).unwrap();
+        assert_eq!(read("stale.txt"), "new\n");
+        assert_eq!(read("only-theirs.txt"), "theirs\n");
+        assert_eq!(read("edited.txt"), "mine\n");
+        assert_eq!(read("only-mine.txt"), "mine\n");
+    }
+
+    #[test]
+    fn test_renames_are_sent_as_a_delete_and_create() {
+        let root = TempDir::new("link");
+        std::fs::write(root.join("new.txt"), "moved\n").unwrap();
+
+        let rename = ChangeKind::Renamed {
+            from: "old.txt".into(),
+            to: "new.txt".into(),
+        };
+        let messages = block_on(messages_for(&root, &rename)).unwrap();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                ServerMessage::Delete { path: from },
+                ServerMessage::Create { path: to, content: Some(content) },
+            ] if from == Path::new("old.txt") && to == Path::new("new.txt") && content == "moved\n"
+        ));
+    }
+}
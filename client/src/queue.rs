@@ -0,0 +1,258 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::*;
+use core::{config::SINK_DIRECTORY, messages::ServerMessage};
+use serde::{Deserialize, Serialize};
+
+/// How many lines the file can carry beyond the live entries before it's rewritten
+const COMPACT_AFTER: usize = 1000;
+
+/// A change waiting for the server to acknowledge it, seq doubles as the message id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub seq: u64,
+    pub message: ServerMessage,
+}
+
+/// A line of the queue file
+#[derive(Serialize, Deserialize)]
+enum Op {
+    Push(Entry),
+    Done(u64),
+}
+
+/// The changes a project has yet to get acknowledged by the server, written ahead to
+/// `.sink/queue.jsonl` so they survive disconnects and the daemon going away. Every push and done
+/// is appended and synced before it counts, the file's rewritten with just the live entries now
+/// and then.
+///
+/// A change that was acknowledged just before a crash, before its done was written, is sent again.
+/// Every message sets a file to a state so that's harmless.
+pub struct Queue {
+    path: PathBuf,
+    file: File,
+    entries: VecDeque<Entry>,
+    next_seq: u64,
+    /// Entries up to this seq have gone out on the current connection and are left as they are
+    sent: u64,
+    /// Lines in the file, live or not
+    lines: usize,
+}
+
+impl Queue {
+    pub fn path(root: &Path) -> PathBuf {
+        root.join(SINK_DIRECTORY).join("queue.jsonl")
+    }
+
+    /// Opens the project's queue, picking up whatever a previous daemon left unacknowledged.
+    pub fn open(root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(root.join(SINK_DIRECTORY))?;
+        let path = Self::path(root);
+        let mut entries = VecDeque::new();
+        let mut last_seq = 0;
+        match File::open(&path) {
+            Result::Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    // a crash mid write leaves half a line at the end
+                    let Result::Ok(op) = serde_json::from_str::<Op>(&line?) else {
+                        break;
+                    };
+                    match op {
+                        Op::Push(entry) => {
+                            last_seq = last_seq.max(entry.seq);
+                            entries.push_back(entry);
+                        }
+                        Op::Done(seq) => entries.retain(|entry: &Entry| entry.seq != seq),
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(Self {
+            file: rewrite(&path, &entries)?,
+            lines: entries.len(),
+            path,
+            entries,
+            next_seq: last_seq + 1,
+            sent: 0,
+        })
+    }
+
+    /// Changes waiting on the server
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Queues a change. One that hasn't gone out yet for the same file is folded into it, the
+    /// server only needs to hear where the file ended up.
+    pub fn push(&mut self, mut message: ServerMessage) -> Result<()> {
+        let unsent = message.path().and_then(|path| {
+            self.entries
+                .iter()
+                .position(|entry| entry.seq > self.sent && entry.message.path() == Some(path))
+        });
+        if let Some(index) = unsent {
+            let older = self
+                .entries
+                .remove(index)
+                .expect("position is in the queue");
+            self.append(&Op::Done(older.seq))?;
+            match supersede(older.message, message) {
+                Some(merged) => message = merged,
+                None => return Ok(()),
+            }
+        }
+        let entry = Entry {
+            seq: self.next_seq,
+            message,
+        };
+        self.next_seq += 1;
+        self.append(&Op::Push(entry.clone()))?;
+        self.entries.push_back(entry);
+        Ok(())
+    }
+
+    /// The server has answered the change, it's not sent again.
+    pub fn done(&mut self, seq: u64) -> Result<()> {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.seq != seq);
+        if self.entries.len() != before {
+            self.append(&Op::Done(seq))?;
+        }
+        Ok(())
+    }
+
+    /// The change queued as seq, while it's waiting on the server
+    pub fn get(&self, seq: u64) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.seq == seq)
+    }
+
+    /// Files with changes waiting on the server
+    pub fn paths(&self) -> HashSet<PathBuf> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.message.path())
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// The oldest change that hasn't gone out on this connection yet.
+    pub fn next_unsent(&mut self) -> Option<Entry> {
+        let entry = self.entries.iter().find(|entry| entry.seq > self.sent)?;
+        self.sent = entry.seq;
+        Some(entry.clone())
+    }
+
+    /// Everything unacknowledged goes out again, for a new connection.
+    pub fn restart(&mut self) {
+        self.sent = 0;
+    }
+
+    fn append(&mut self, op: &Op) -> Result<()> {
+        let mut line = serde_json::to_vec(op)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.lines += 1;
+        if self.lines > self.entries.len() + COMPACT_AFTER {
+            self.file = rewrite(&self.path, &self.entries)?;
+            self.lines = self.entries.len();
+        }
+        Ok(())
+    }
+}
+
+/// Writes a queue file with only the live entries, swapping it in with a rename so a crash leaves
+/// either the old file or the new one. Returns the new file ready to append to.
+fn rewrite(path: &Path, entries: &VecDeque<Entry>) -> Result<File> {
+    let temp = path.with_extension("jsonl.tmp");
+    let mut file = File::create(&temp)?;
+    for entry in entries {
+        let mut line = serde_json::to_vec(&Op::Push(entry.clone()))?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+/// What the server needs to hear once newer has happened on top of older, None for nothing.
+fn supersede(older: ServerMessage, newer: ServerMessage) -> Option<ServerMessage> {
+    match (older, newer) {
+        // the server never heard of the file, it doesn't need to
+        (ServerMessage::Create { .. }, ServerMessage::Delete { .. }) => None,
+        (ServerMessage::Create { .. }, ServerMessage::Modify { path, content }) => {
+            Some(ServerMessage::Create {
+                path,
+                content: Some(content),
+            })
+        }
+        (_, newer) => Some(newer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::testing::TempDir;
+
+    fn modify(path: &str, content: &str) -> ServerMessage {
+        ServerMessage::Modify {
+            path: path.into(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_queue_compacts_and_survives_reopening() {
+        let root = TempDir::new("queue");
+
+        let mut queue = Queue::open(&root).unwrap();
+        queue.push(modify("b", "1")).unwrap();
+        queue
+            .push(ServerMessage::Create {
+                path: "a".into(),
+                content: None,
+            })
+            .unwrap();
+        let sent = queue.next_unsent().unwrap();
+        // b's gone out, so the next edit to it can't be folded in
+        queue.push(modify("b", "2")).unwrap();
+        queue.push(modify("a", "3")).unwrap();
+        queue.done(sent.seq).unwrap();
+        drop(queue);
+
+        // a crash mid write
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(Queue::path(&root))
+            .unwrap();
+        file.write_all(br#"{"Push":{"seq":9"#).unwrap();
+
+        let mut queue = Queue::open(&root).unwrap();
+        let messages = std::iter::from_fn(|| queue.next_unsent())
+            .map(|entry| entry.message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                modify("b", "2"),
+                ServerMessage::Create {
+                    path: "a".into(),
+                    content: Some("3".to_string()),
+                },
+            ]
+        );
+    }
+}
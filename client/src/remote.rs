@@ -0,0 +1,290 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Permissions,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::*;
+use core::{
+    config::TEMP_SUFFIX,
+    messages::ClientMessage,
+    objects::{FileObject, Objects},
+    patch,
+    watcher::{ChangeKind, Watcher},
+};
+use seahash::SeaHasher;
+use tokio::io::AsyncWriteExt;
+
+/// Writes the changes other participants on the stream make into a local project. Everyone on a
+/// stream is sharing the one project, whatever their copy of it is called, so changes always go to
+/// the root here.
+pub struct Remote {
+    root: PathBuf,
+}
+
+impl Remote {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    /// Applies a change from the server, the watcher's told about the write first so it isn't
+    /// reported and sent straight back. Returns the local root and the change made to it, if the
+    /// message changed a file.
+    pub async fn handle<W: Watcher + Send>(
+        &mut self,
+        watcher: &mut W,
+        message: ClientMessage,
+    ) -> Result<Option<(PathBuf, ChangeKind)>> {
+        let kind = match message {
+            // the stream's only the one project
+            ClientMessage::Project { .. } => return Ok(None),
+            // the link asks for a snapshot and hands it to reconcile
+            ClientMessage::Resync { .. } | ClientMessage::Snapshot { .. } => return Ok(None),
+            ClientMessage::Create { path, content } => {
+                let content = content.unwrap_or_default();
+                let local = self.local_path(&path)?;
+                check_base(&local, None, Some(&content)).await?;
+                write(watcher, &local, &content).await?;
+                ChangeKind::Created(path)
+            }
+            ClientMessage::Modify {
+                path,
+                content,
+                base,
+            } => {
+                let local = self.local_path(&path)?;
+                check_base(&local, base, Some(&content)).await?;
+                write(watcher, &local, &content).await?;
+                ChangeKind::Modified(path)
+            }
+            ClientMessage::Delete { path, base } => {
+                let local = self.local_path(&path)?;
+                check_base(&local, base, None).await?;
+                watcher.expect_write(&local, None);
+                match tokio::fs::remove_file(&local).await {
+                    Result::Ok(()) => {}
+                    // already gone, there's nothing to do and no event to expect
+                    Err(err) if err.kind() == ErrorKind::NotFound => {
+                        watcher.forget_write(&local);
+                        return Ok(None);
+                    }
+                    Err(err) => {
+                        watcher.forget_write(&local);
+                        return Err(err.into());
+                    }
+                }
+                ChangeKind::Deleted(path)
+            }
+        };
+        Ok(Some((self.root.clone(), kind)))
+    }
+
+    /// Brings the project in line with the server's copy after changes from the stream were lost.
+    /// The server's copy of a file wins, apart from those in pending which have local changes on
+    /// their way to it. Returns the changes made here and the files only we have, those are sent
+    /// to the server rather than deleted here as a server that's restarted has nothing at all.
+    pub async fn reconcile<W: Watcher + Send>(
+        &self,
+        watcher: &mut W,
+        files: &HashMap<PathBuf, String>,
+        pending: &HashSet<PathBuf>,
+    ) -> Result<(Vec<ChangeKind>, Vec<PathBuf>)> {
+        let local = Objects::from_directory::<SeaHasher>(&self.root)
+            .await?
+            .objects;
+        let mut changes = Vec::new();
+        for (path, content) in files.iter().filter(|(path, _)| !pending.contains(*path)) {
+            let object = FileObject::from_bytes::<SeaHasher>(content.as_bytes());
+            let kind = match local.get(path) {
+                Some(existing) if *existing == object => continue,
+                Some(_) => ChangeKind::Modified(path.clone()),
+                None => ChangeKind::Created(path.clone()),
+            };
+            write(watcher, &self.local_path(path)?, content).await?;
+            changes.push(kind);
+        }
+        let missing = local
+            .into_keys()
+            .filter(|path| !files.contains_key(path) && !pending.contains(path))
+            .collect();
+        Ok((changes, missing))
+    }
+
+    /// Where a path in the project lives locally, paths that would escape the project are
+    /// refused.
+    fn local_path(&self, path: &Path) -> Result<PathBuf> {
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(anyhow!("{path:?} is outside of the project"));
+        }
+        Ok(self.root.join(path))
+    }
+}
+
+/// Refuses a remote change when the local file has moved on from base, the version the change
+/// was made against, None being no file at all. A file that's already where the change leaves it
+/// is fine.
+async fn check_base(path: &Path, base: Option<u64>, content: Option<&str>) -> Result<()> {
+    let current = match tokio::fs::read_to_string(path).await {
+        Result::Ok(current) => Some(current),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    if current.as_deref() == content {
+        return Ok(());
+    }
+    if current.as_deref().map(patch::version) != base {
+        return Err(anyhow!(
+            "{path:?} has changed locally since the remote change was made, leaving it be"
+        ));
+    }
+    Ok(())
+}
+
+/// Editors and build tools only ever see the old file or the new one, the content is written to
+/// a temp file beside it which is synced and renamed over it. The file keeps its mode.
+async fn write<W: Watcher>(watcher: &mut W, path: &Path, content: &str) -> Result<()> {
+    let parent = path
+        .parent()
+        .ok_or(anyhow!("{path:?} has no parent directory"))?;
+    let name = path
+        .file_name()
+        .ok_or(anyhow!("{path:?} has no file name"))?;
+    tokio::fs::create_dir_all(parent).await?;
+    let permissions = match tokio::fs::metadata(path).await {
+        Result::Ok(metadata) => Some(metadata.permissions()),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+
+    let object = FileObject::from_bytes::<SeaHasher>(content.as_bytes());
+    watcher.expect_write(path, Some(object));
+    let temp = parent.join(format!(".{}{TEMP_SUFFIX}", name.to_string_lossy()));
+    if let Err(err) = replace(&temp, path, content, permissions).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        watcher.forget_write(path);
+        return Err(err);
+    }
+    // the rename only survives a crash once the directory's synced too
+    tokio::fs::File::open(parent).await?.sync_all().await?;
+    Ok(())
+}
+
+async fn replace(
+    temp: &Path,
+    path: &Path,
+    content: &str,
+    permissions: Option<Permissions>,
+) -> Result<()> {
+    let mut file = tokio::fs::File::create(temp).await?;
+    file.write_all(content.as_bytes()).await?;
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions).await?;
+    }
+    file.sync_all().await?;
+    tokio::fs::rename(temp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::testing::{TempDir, block_on};
+    use core::watcher::{MockWatcher, WatchCall};
+
+    #[test]
+    fn test_remote_changes_are_written_and_expected() {
+        let root = TempDir::new("remote");
+        let (mut watcher, handle) = MockWatcher::new();
+        let mut remote = Remote::new(&root);
+
+        // whoever made the change has their copy somewhere else, it lands in ours all the same
+        let project = ClientMessage::Project {
+            root: "their-copy".into(),
+        };
+        let create = ClientMessage::Create {
+            path: "src/lib.rs".into(),
+            content: Some("pub fn sink() {}\n".to_string()),
+        };
+        block_on(remote.handle(&mut watcher, project)).unwrap();
+        let change = block_on(remote.handle(&mut watcher, create)).unwrap();
+        assert_eq!(
+            change,
+            Some((root.to_path_buf(), ChangeKind::Created("src/lib.rs".into())))
+        );
+
+        let path = root.join("src/lib.rs");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "pub fn sink() {}\n"
+        );
+        let object = FileObject::from_bytes::<SeaHasher>(b"pub fn sink() {}\n");
+        assert_eq!(
+            handle.calls(),
+            vec![WatchCall::ExpectWrite(path, Some(object))]
+        );
+
+        let escape = ClientMessage::Delete {
+            path: "../elsewhere".into(),
+            base: None,
+        };
+        assert!(block_on(remote.handle(&mut watcher, escape)).is_err());
+    }
+
+    #[test]
+    fn test_remote_changes_keep_the_mode_and_refuse_local_edits() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = TempDir::new("remote-base");
+        let path = root.join("run.sh");
+        std::fs::write(&path, "echo one\n").unwrap();
+        std::fs::set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+        let (mut watcher, handle) = MockWatcher::new();
+        let mut remote = Remote::new(&root);
+
+        let modify = |content: &str, base: &str| ClientMessage::Modify {
+            path: "run.sh".into(),
+            content: content.to_string(),
+            base: Some(patch::version(base)),
+        };
+        block_on(remote.handle(&mut watcher, modify("echo two\n", "echo one\n"))).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "echo two\n");
+
+        // made against a version this file's never been at
+        assert!(
+            block_on(remote.handle(&mut watcher, modify("echo three\n", "echo one\n"))).is_err()
+        );
+        let create = ClientMessage::Create {
+            path: "run.sh".into(),
+            content: None,
+        };
+        assert!(block_on(remote.handle(&mut watcher, create)).is_err());
+        // the server had no copy, so it was made against no file at all
+        let unbased = ClientMessage::Modify {
+            path: "run.sh".into(),
+            content: "echo four\n".to_string(),
+            base: None,
+        };
+        assert!(block_on(remote.handle(&mut watcher, unbased)).is_err());
+        let delete = ClientMessage::Delete {
+            path: "run.sh".into(),
+            base: Some(patch::version("echo one\n")),
+        };
+        assert!(block_on(remote.handle(&mut watcher, delete)).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "echo two\n");
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+        // nothing's left expected, the next edit to the file is reported
+        assert!(
+            !handle
+                .calls()
+                .iter()
+                .any(|call| matches!(call, WatchCall::ExpectWrite(_, None)))
+        );
+    }
+}
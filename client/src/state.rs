@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::*;
+use core::{
+    config::{SINK_DIRECTORY, SyncConfig},
+    objects::{FileObject, Objects},
+    watcher::{ChangeEvent, ChangeKind},
+};
+use seahash::SeaHasher;
+use serde::{Deserialize, Serialize};
+
+/// A project the daemon has open and where it's synced to, enough to pick it back up after a
+/// restart.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OpenProject {
+    pub root: PathBuf,
+    pub sync: SyncConfig,
+    /// The last change from the stream the project has, the ones after it are caught up on. None
+    /// for a project that's never been open.
+    #[serde(default)]
+    pub seen: Option<u64>,
+}
+
+/// The projects the daemon had open, kept in `projects.json` in the state dir so the next daemon
+/// opens them again.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct State {
+    pub projects: Vec<OpenProject>,
+}
+
+impl State {
+    pub fn path() -> Result<PathBuf> {
+        Ok(core::state_dir()?.join("projects.json"))
+    }
+
+    /// What the last daemon left, nothing when there wasn't one.
+    pub async fn load() -> Result<Self> {
+        match tokio::fs::read(Self::path()?).await {
+            Result::Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        write(&Self::path()?, &serde_json::to_vec_pretty(self)?).await
+    }
+}
+
+/// Where a project's files were at when the daemon last had it open
+fn manifest_path(root: &Path) -> PathBuf {
+    root.join(SINK_DIRECTORY).join("manifest.json")
+}
+
+/// Writes down the project's files as they are now, whatever's different the next time it's
+/// opened changed while no one was watching.
+pub async fn record(root: &Path) -> Result<()> {
+    let objects = Objects::from_directory::<SeaHasher>(root).await?;
+    tokio::fs::create_dir_all(root.join(SINK_DIRECTORY)).await?;
+    write(&manifest_path(root), &serde_json::to_vec(&objects.objects)?).await
+}
+
+/// The changes made to the project since it was last recorded, none if it never was.
+pub async fn catch_up(root: &Path) -> Result<Vec<ChangeEvent>> {
+    let recorded = match tokio::fs::read(manifest_path(root)).await {
+        Result::Ok(bytes) => serde_json::from_slice::<HashMap<PathBuf, FileObject>>(&bytes)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let now = Objects::from_directory::<SeaHasher>(root).await?;
+    let delta = Objects::recorded(root, recorded).diff(&now);
+
+    let mut events = Vec::new();
+    for (path, object) in delta.added {
+        let kind = ChangeKind::Created(path);
+        events.push(ChangeEvent::new(root, kind, Some(object)));
+    }
+    for (path, _) in delta.removed {
+        events.push(ChangeEvent::new(root, ChangeKind::Deleted(path), None));
+    }
+    for (path, object) in delta.modified {
+        let kind = ChangeKind::Modified(path);
+        events.push(ChangeEvent::new(root, kind, Some(object)));
+    }
+    for (to, (from, object)) in delta.renamed {
+        let kind = ChangeKind::Renamed { from, to };
+        events.push(ChangeEvent::new(root, kind, Some(object)));
+    }
+    Ok(events)
+}
+
+/// Swaps the file in with a rename so a crash leaves the old one or the new one.
+async fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp = path.with_extension("json.tmp");
+    tokio::fs::write(&temp, contents).await?;
+    tokio::fs::rename(&temp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::testing::{TempDir, block_on};
+
+    #[test]
+    fn test_catching_up_finds_what_changed_since_the_last_record() {
+        let root = TempDir::new("state");
+        assert!(block_on(catch_up(&root)).unwrap().is_empty());
+
+        std::fs::write(root.join("kept.txt"), "kept\n").unwrap();
+        std::fs::write(root.join("edited.txt"), "before\n").unwrap();
+        std::fs::write(root.join("gone.txt"), "gone\n").unwrap();
+        block_on(record(&root)).unwrap();
+
+        std::fs::write(root.join("edited.txt"), "after\n").unwrap();
+        std::fs::remove_file(root.join("gone.txt")).unwrap();
+        std::fs::write(root.join("new.txt"), "new\n").unwrap();
+        let mut kinds = block_on(catch_up(&root))
+            .unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect::<Vec<_>>();
+        kinds.sort_by_key(|kind| kind.paths()[0].to_path_buf());
+        assert_eq!(
+            kinds,
+            vec![
+                ChangeKind::Modified("edited.txt".into()),
+                ChangeKind::Deleted("gone.txt".into()),
+                ChangeKind::Created("new.txt".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Directory inside a project sink keeps its own files in, it's never synced
+pub const SINK_DIRECTORY: &str = ".sink";
+
+/// Ending of the files remote changes are written to before they're renamed into place, never
+/// synced
+pub const TEMP_SUFFIX: &str = ".sink-tmp";
+
+/// Per project options, read from `.sink/config.json` in the project root.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ProjectConfig {
+    pub poll: PollConfig,
+    pub sync: SyncConfig,
+}
+
+impl ProjectConfig {
+    pub fn path(root: &Path) -> PathBuf {
+        root.join(SINK_DIRECTORY).join("config.json")
+    }
+
+    /// Loads the project's config, a project without one gets the defaults.
+    pub async fn load(root: &Path) -> Result<Self> {
+        match tokio::fs::read_to_string(Self::path(root)).await {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Where a project's changes are sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SyncConfig {
+    /// The server's websocket endpoint, only ws:// for now
+    pub server: String,
+    /// The stream the project joins, the name of the project's directory when unset
+    pub stream: Option<String>,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            server: "ws://127.0.0.1:9999/ws".to_string(),
+            stream: None,
+        }
+    }
+}
+
+/// How hard the polling watcher is allowed to work on a project.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PollConfig {
+    /// Fraction of a single core scanning the project may use, 0.02 is 2% of the time
+    pub cpu_budget: f64,
+    /// Shortest wait between scans, used while the project is busy
+    pub min_interval_ms: u64,
+    /// Longest wait between scans an idle project backs off to, unless the budget needs longer
+    pub max_interval_ms: u64,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self {
+            cpu_budget: 0.02,
+            min_interval_ms: 1000,
+            max_interval_ms: 30_000,
+        }
+    }
+}
+
+impl PollConfig {
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_millis(self.min_interval_ms)
+    }
+
+    pub fn max_interval(&self) -> Duration {
+        Duration::from_millis(self.max_interval_ms)
+    }
+}
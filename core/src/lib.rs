@@ -1,17 +1,27 @@
 use std::{
-    fs::create_dir_all,
+    ffi::OsString,
+    fs::DirBuilder,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
     path::{Path, PathBuf},
 };
 
+use anyhow::*;
+use nix::unistd::getuid;
+
+pub mod config;
 pub mod messages;
 pub mod objects;
+pub mod patch;
 pub mod project;
+pub mod status;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod watcher;
 
 // todo: Probably be in client?
 pub fn is_daemon_running() -> bool {
-    match std::fs::read_to_string(pid_path()) {
-        Ok(pid_str) => {
+    match pid_path().and_then(|path| Ok(std::fs::read_to_string(path)?)) {
+        Result::Ok(pid_str) => {
             let pid = pid_str.trim();
             std::process::Command::new("kill")
                 .arg("-0")
@@ -20,21 +30,84 @@ pub fn is_daemon_running() -> bool {
                 .map(|output| output.status.success())
                 .unwrap_or(false)
         }
-        Err(_) => false,
+        Result::Err(_) => false,
+    }
+}
+
+/// Where the daemon keeps its socket, pid file and logs. `SINK_HOME` when it's set so separate
+/// instances can run side by side, otherwise under `XDG_RUNTIME_DIR` or a directory in the temp
+/// dir named after the user. Created only readable by us, one anyone else can get into is refused.
+pub fn runtime_dir() -> Result<PathBuf> {
+    let uid = getuid();
+    let dir = resolve_runtime_dir(
+        std::env::var_os("SINK_HOME"),
+        std::env::var_os("XDG_RUNTIME_DIR"),
+        uid.as_raw(),
+    );
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    // the temp dir is shared, someone else could have made the directory first
+    let metadata = std::fs::metadata(&dir)?;
+    if metadata.uid() != uid.as_raw() {
+        return Err(anyhow!("{dir:?} belongs to another user"));
+    }
+    // it could be a directory that's used for other things, like SINK_HOME=~, so it's not ours
+    // to lock down
+    if metadata.mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "{dir:?} is open to other users, chmod it to 700 or point SINK_HOME elsewhere"
+        ));
     }
+    Ok(dir)
+}
+
+fn resolve_runtime_dir(
+    sink_home: Option<OsString>,
+    xdg_runtime_dir: Option<OsString>,
+    uid: u32,
+) -> PathBuf {
+    let not_empty = |var: Option<OsString>| var.filter(|var| !var.is_empty()).map(PathBuf::from);
+    if let Some(home) = not_empty(sink_home) {
+        home
+    } else if let Some(runtime) = not_empty(xdg_runtime_dir) {
+        runtime.join("sink")
+    } else {
+        std::env::temp_dir().join(format!("sink-{uid}"))
+    }
+}
+
+/// Where the daemon keeps what it needs to survive a reboot, like the projects it had open.
+/// `SINK_HOME` when it's set, otherwise `XDG_STATE_HOME` or `~/.local/state`.
+pub fn state_dir() -> Result<PathBuf> {
+    let dir = resolve_state_dir(
+        std::env::var_os("SINK_HOME"),
+        std::env::var_os("XDG_STATE_HOME"),
+        std::env::var_os("HOME"),
+    )?;
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    Ok(dir)
 }
 
-pub fn pid_path() -> PathBuf {
-    let package_name = "sink";
-    let mut pid_path = std::env::temp_dir();
-    pid_path.push(package_name);
-    match create_dir_all(&pid_path) {
-        Ok(_) => {}
-        Err(_) => {}
+fn resolve_state_dir(
+    sink_home: Option<OsString>,
+    xdg_state_home: Option<OsString>,
+    home: Option<OsString>,
+) -> Result<PathBuf> {
+    let not_empty = |var: Option<OsString>| var.filter(|var| !var.is_empty()).map(PathBuf::from);
+    if let Some(sink_home) = not_empty(sink_home) {
+        Ok(sink_home)
+    } else if let Some(state) = not_empty(xdg_state_home) {
+        Ok(state.join("sink"))
+    } else if let Some(home) = not_empty(home) {
+        Ok(home.join(".local/state/sink"))
+    } else {
+        Err(anyhow!(
+            "HOME isn't set, there's nowhere to keep sink's state"
+        ))
     }
-    pid_path.set_file_name(package_name);
-    pid_path.set_extension("pid");
-    pid_path
+}
+
+pub fn pid_path() -> Result<PathBuf> {
+    Ok(runtime_dir()?.join("sink.pid"))
 }
 
 fn path_is_child(path: &Path, parent: &Path) -> bool {
@@ -45,10 +118,11 @@ fn path_is_child(path: &Path, parent: &Path) -> bool {
         }
         path_ref = next_path.parent();
     }
-    return false;
+    false
 }
 
-fn path_is_parent(path: &Path, child: &Path) -> bool {
+/// Whether child is somewhere under path
+pub fn path_is_parent(path: &Path, child: &Path) -> bool {
     let mut path_ref = child.parent();
     while let Some(next_path) = path_ref {
         if next_path == path {
@@ -56,5 +130,42 @@ fn path_is_parent(path: &Path, child: &Path) -> bool {
         }
         path_ref = next_path.parent();
     }
-    return false;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_dir_prefers_sink_home() {
+        let home = Some(OsString::from("/tmp/sink-home"));
+        let runtime = Some(OsString::from("/run/user/1000"));
+        assert_eq!(
+            resolve_runtime_dir(home, runtime.clone(), 1000),
+            Path::new("/tmp/sink-home")
+        );
+        assert_eq!(
+            resolve_runtime_dir(Some(OsString::new()), runtime, 1000),
+            Path::new("/run/user/1000/sink")
+        );
+        assert_eq!(
+            resolve_runtime_dir(None, None, 1000),
+            std::env::temp_dir().join("sink-1000")
+        );
+    }
+
+    #[test]
+    fn test_state_dir_falls_back_to_home() {
+        let home = Some(OsString::from("/home/sink"));
+        assert_eq!(
+            resolve_state_dir(None, Some(OsString::from("/state")), home.clone()).unwrap(),
+            Path::new("/state/sink")
+        );
+        assert_eq!(
+            resolve_state_dir(None, None, home).unwrap(),
+            Path::new("/home/sink/.local/state/sink")
+        );
+        assert!(resolve_state_dir(None, None, None).is_err());
+    }
 }
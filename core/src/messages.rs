@@ -1,27 +1,232 @@
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::*;
-use serde::{Deserialize, Serialize};
-use tokio::io::AsyncReadExt;
+use nix::errno::Errno;
+use nix::unistd::{AccessFlags, access, getuid};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::patch::Hunk;
+use crate::status::EventRecord;
+use crate::watcher::WatchError;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::sync::oneshot::error::TryRecvError;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Bumped whenever a message changes in a way an older peer couldn't read.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Version byte followed by the big endian length of the body
+const HEADER_LEN: usize = 5;
+/// Anything bigger is a broken or hostile peer, not a message
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Every framed message is wrapped in an envelope, the id lets replies refer back to the message
+/// they're for. Messages from peers still speaking bare json get an id of 0.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Envelope<T> {
+    pub id: u64,
+    pub body: T,
+}
+
+/// Frames a message: a version byte, the length of the body as a big endian u32 then the envelope
+/// encoded as CBOR. The same frames are used over the command socket and the websocket.
+pub fn encode<T: Serialize>(id: u64, body: &T) -> Result<Vec<u8>> {
+    let mut frame = vec![PROTOCOL_VERSION, 0, 0, 0, 0];
+    ciborium::into_writer(&Envelope { id, body }, &mut frame)?;
+    let len = frame.len() - HEADER_LEN;
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!("message of {len} bytes is too large to send"));
+    }
+    frame[1..HEADER_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(frame)
+}
+
+/// Reads a frame made by [`encode`]. A frame starting with `{` is bare json from a peer that
+/// predates framing, we still understand those.
+pub fn decode<T: DeserializeOwned>(frame: &[u8]) -> Result<Envelope<T>> {
+    match frame.first() {
+        None => Err(anyhow!("empty frame")),
+        Some(b'{') => Ok(Envelope {
+            id: 0,
+            body: serde_json::from_slice(frame)?,
+        }),
+        Some(&PROTOCOL_VERSION) => {
+            let len = body_len(frame)?;
+            let body = &frame[HEADER_LEN..];
+            if body.len() != len {
+                return Err(anyhow!(
+                    "frame says it has {len} bytes but {} arrived",
+                    body.len()
+                ));
+            }
+            Ok(ciborium::from_reader(body)?)
+        }
+        Some(version) => Err(anyhow!(
+            "unsupported protocol version {version}, we speak {PROTOCOL_VERSION}"
+        )),
+    }
+}
+
+fn body_len(frame: &[u8]) -> Result<usize> {
+    let header: [u8; 4] = frame
+        .get(1..HEADER_LEN)
+        .ok_or(anyhow!("frame is missing its header"))?
+        .try_into()?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!("frame of {len} bytes is too large"));
+    }
+    Ok(len)
+}
+
+/// Reads the next whole frame off a stream for [`decode`], None once the stream has closed. Bare
+/// json has no length so it runs to the end of the stream.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut frame = vec![0; 1];
+    if reader.read(&mut frame).await? == 0 {
+        return Ok(None);
+    }
+    if frame[0] == b'{' {
+        reader.read_to_end(&mut frame).await?;
+        return Ok(Some(frame));
+    }
+    frame.resize(HEADER_LEN, 0);
+    reader.read_exact(&mut frame[1..]).await?;
+    let len = body_len(&frame)?;
+    frame.resize(HEADER_LEN + len, 0);
+    reader.read_exact(&mut frame[HEADER_LEN..]).await?;
+    Ok(Some(frame))
+}
+
+/// Optional parts of the protocol, a connection only uses the ones both ends support
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Content is compressed on the wire
+    Compression,
+    /// Large files are sent across several messages
+    Chunking,
+    /// Content is raw bytes rather than utf8 strings
+    BinaryContent,
+}
+
+/// What this build of sink supports
+pub const FEATURES: &[Feature] = &[];
+
+/// The first message a client sends when it connects, nothing else is accepted until the server
+/// has welcomed it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub version: u8,
+    /// Identifies the client across reconnects
+    pub client_id: String,
+    pub user: String,
+    /// Every change the client makes is shared with the other clients on the same stream
+    pub stream: String,
+    pub features: Vec<Feature>,
+    /// The sequence number of the last change the client has from the stream, it's sent every
+    /// change since. None for a client starting fresh.
+    #[serde(default)]
+    pub resume_from: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum HelloReply {
+    /// The client can start sending, with the features both sides support
+    Welcome { version: u8, features: Vec<Feature> },
+    /// The server won't talk to the client, it's disconnected after this
+    Refused { reason: String },
+}
+
+impl Hello {
+    pub fn new(client_id: &str, user: &str, stream: &str) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            client_id: client_id.to_string(),
+            user: user.to_string(),
+            stream: stream.to_string(),
+            features: FEATURES.to_vec(),
+            resume_from: None,
+        }
+    }
+
+    /// How a server supporting the given features answers this hello.
+    pub fn reply(&self, supported: &[Feature]) -> HelloReply {
+        if self.version != PROTOCOL_VERSION {
+            return HelloReply::Refused {
+                reason: format!(
+                    "client speaks protocol version {} but the server speaks {PROTOCOL_VERSION}",
+                    self.version
+                ),
+            };
+        }
+        if self.stream.is_empty() {
+            return HelloReply::Refused {
+                reason: "no stream was given".to_string(),
+            };
+        }
+        HelloReply::Welcome {
+            version: PROTOCOL_VERSION,
+            features: self
+                .features
+                .iter()
+                .copied()
+                .filter(|feature| supported.contains(feature))
+                .collect(),
+        }
+    }
+}
+
+/// A change another participant on the stream made, sent to every other client on the stream
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
     /// Deletes a file
-    Delete { path: PathBuf },
+    Delete {
+        path: PathBuf,
+        /// [`crate::patch::version`] of the server's copy before the change, None if it wasn't
+        /// readable
+        #[serde(default)]
+        base: Option<u64>,
+    },
     /// Creates a new file
     Create {
         path: PathBuf,
         content: Option<String>,
     },
     /// Ovewrites the file with new content
-    Modify { path: PathBuf, content: String },
+    Modify {
+        path: PathBuf,
+        content: String,
+        /// [`crate::patch::version`] of the server's copy before the change, None if it didn't
+        /// have one
+        #[serde(default)]
+        base: Option<u64>,
+    },
+    /// The project the changes that follow belong to, the root is what the participant who made
+    /// them sent in their [`ServerMessage::Project`]
+    Project { root: PathBuf },
+    /// Changes the client hasn't seen are gone, it has to reconcile its projects with the server's
+    /// copy, asking for it with a [`ServerMessage::Snapshot`]. Changes carry on after seq.
+    Resync { seq: u64 },
+    /// Every file in the server's copy of the project, the answer to a
+    /// [`ServerMessage::Snapshot`]. Only ever sent to the client that asked.
+    Snapshot { files: HashMap<PathBuf, String> },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Everything the server sends a client once it's been welcomed, a change's envelope id is its
+/// sequence number in the stream
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ToClient {
+    Response(Response),
+    Change(ClientMessage),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
     /// Deletes a file
@@ -33,8 +238,81 @@ pub enum ServerMessage {
     },
     /// Ovewrites the file with new content
     Modify { path: PathBuf, content: String },
+    /// Changes only the lines in the hunks, base is the [`crate::patch::version`] of the file the
+    /// hunks were made against. If the server's copy isn't at that version it's refused and the
+    /// whole file should be sent with a Modify instead.
+    Patch {
+        path: PathBuf,
+        base: u64,
+        hunks: Vec<Hunk>,
+    },
     /// Changes the root of all future operations
     Project { root: PathBuf },
+    /// Asks for the server's copy of the current project, it comes back as a
+    /// [`ClientMessage::Snapshot`] ahead of the response
+    Snapshot,
+}
+
+impl ServerMessage {
+    /// The file the message changes, None when it's not about a file
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            ServerMessage::Delete { path }
+            | ServerMessage::Create { path, .. }
+            | ServerMessage::Modify { path, .. }
+            | ServerMessage::Patch { path, .. } => Some(path),
+            ServerMessage::Project { .. } | ServerMessage::Snapshot => None,
+        }
+    }
+}
+
+/// Why the server rejected a message
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The message couldn't be read
+    Malformed,
+    /// The message can't be applied, like creating a file where a directory is
+    Invalid,
+    /// The file the message refers to doesn't exist on the server
+    NotFound,
+    /// Something went wrong on the server, sending it again might work
+    Internal,
+}
+
+/// The server answers every message a client sends with one of these, the id is the id of the
+/// envelope the message came in.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum Response {
+    /// The change landed, seq is its place in the stream if it was shared with the rest of it
+    Ack {
+        id: u64,
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    Rejected {
+        id: u64,
+        code: ErrorCode,
+        message: String,
+    },
+    /// A patch was made against a version of the file the server no longer has, version is the
+    /// one it does have. The client should send the whole file instead.
+    Conflict {
+        id: u64,
+        path: PathBuf,
+        version: u64,
+    },
+}
+
+impl Response {
+    /// The id of the message this is the response to
+    pub fn id(&self) -> u64 {
+        match self {
+            Response::Ack { id, .. }
+            | Response::Rejected { id, .. }
+            | Response::Conflict { id, .. } => *id,
+        }
+    }
 }
 
 impl TryFrom<&str> for ServerMessage {
@@ -48,31 +326,197 @@ impl TryFrom<&str> for ServerMessage {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
-    Open { path: PathBuf },
-    Close { path: PathBuf },
-    Shutdown { caller: String },
+    Open {
+        path: PathBuf,
+    },
+    Close {
+        path: PathBuf,
+    },
+    Shutdown {
+        caller: String,
+    },
+    /// Replies with a [`crate::status::DaemonStatus`]
+    Status,
+    /// Replies with the [`crate::watcher::WatchedRoot`]s being watched
+    List,
+    /// Replies with [`crate::status::Stats`]
+    Stats,
+    /// Replies with up to limit of the most recent [`crate::status::EventRecord`]s, oldest first
+    Events {
+        limit: Option<usize>,
+    },
+    /// Keeps the connection open after the reply, writing every change in the projects under the
+    /// paths as a line of json. No projects or paths means all of them.
+    Subscribe {
+        projects: Vec<PathBuf>,
+        paths: Vec<PathBuf>,
+    },
+}
+
+fn socket_path() -> Result<PathBuf> {
+    Ok(crate::runtime_dir()?.join("sink.sock"))
 }
 
-fn socket_path() -> PathBuf {
-    let package_name = env!("CARGO_PKG_NAME");
-    let mut socket_path = std::env::temp_dir();
-    socket_path.push(package_name);
-    socket_path.set_file_name(package_name);
-    socket_path.set_extension("sock");
-    socket_path
+/// What went wrong running a command
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The command couldn't be read
+    Malformed,
+    AlreadyWatched,
+    /// The path is inside a project that's already being watched
+    InsideWatched,
+    NotWatched,
+    NotFound,
+    /// The caller isn't allowed to run the command
+    Forbidden,
+    Failed,
+}
+
+/// The daemon's answer to a command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum Reply {
+    Ok,
+    Error {
+        kind: ErrorKind,
+        message: String,
+    },
+    /// What the command asked for, the shape depends on the command
+    Data {
+        data: serde_json::Value,
+    },
+}
+
+impl Reply {
+    /// Describes why a command failed, keeping the kind of any error the caller can act on.
+    pub fn error(err: &Error) -> Self {
+        let kind = if let Some(err) = err.downcast_ref::<WatchError>() {
+            match err {
+                WatchError::AlreadyWatched(_) => ErrorKind::AlreadyWatched,
+                WatchError::InsideWatched(_) => ErrorKind::InsideWatched,
+                WatchError::NotWatched(_) => ErrorKind::NotWatched,
+            }
+        } else if err
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+        {
+            ErrorKind::NotFound
+        } else {
+            ErrorKind::Failed
+        };
+        Reply::Error {
+            kind,
+            message: format!("{err:#}"),
+        }
+    }
+
+    pub fn data<T: Serialize>(data: &T) -> Result<Self> {
+        Ok(Reply::Data {
+            data: serde_json::to_value(data)?,
+        })
+    }
+
+    /// Turns an error reply back into an error, data is read as T.
+    pub fn into_result<T: DeserializeOwned>(self) -> Result<Option<T>> {
+        match self {
+            Reply::Ok => Ok(None),
+            Reply::Error { kind, message } => Err(anyhow!("{message} ({kind:?})")),
+            Reply::Data { data } => Ok(Some(serde_json::from_value(data)?)),
+        }
+    }
 }
 
 impl Command {
-    pub fn send(&self) -> Result<()> {
-        let stream = UnixStream::connect(socket_path())?;
-        serde_json::to_writer(stream, self)?;
-        Ok(())
+    /// The projects the command touches, the caller has to be able to read and write them.
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            Command::Open { path } | Command::Close { path } => vec![path],
+            Command::Subscribe { projects, .. } => projects.iter().map(PathBuf::as_path).collect(),
+            Command::Shutdown { .. }
+            | Command::Status
+            | Command::List
+            | Command::Stats
+            | Command::Events { .. } => Vec::new(),
+        }
+    }
+
+    pub fn send(&self) -> Result<Reply> {
+        CommandConnection::connect()?.send(self)
+    }
+}
+
+/// A connection to the daemon's command socket that can carry any number of commands.
+pub struct CommandConnection {
+    stream: UnixStream,
+    next_id: u64,
+}
+
+impl CommandConnection {
+    pub fn connect() -> Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(socket_path()?)?,
+            next_id: 1,
+        })
+    }
+
+    /// Sends a command and waits for the daemon's reply to it.
+    pub fn send(&mut self, command: &Command) -> Result<Reply> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stream.write_all(&encode(id, command)?)?;
+        let mut frame = vec![0; HEADER_LEN];
+        self.stream.read_exact(&mut frame)?;
+        frame.resize(HEADER_LEN + body_len(&frame)?, 0);
+        self.stream.read_exact(&mut frame[HEADER_LEN..])?;
+        let reply = decode::<Reply>(&frame)?;
+        if reply.id != id {
+            return Err(anyhow!("daemon replied to {} instead of {id}", reply.id));
+        }
+        Ok(reply.body)
+    }
+
+    /// Sends a [`Command::Subscribe`], the connection becomes the feed of json lines that follow.
+    pub fn subscribe(
+        mut self,
+        projects: Vec<PathBuf>,
+        paths: Vec<PathBuf>,
+    ) -> Result<Lines<BufReader<UnixStream>>> {
+        self.send(&Command::Subscribe { projects, paths })?
+            .into_result::<()>()?;
+        Ok(BufReader::new(self.stream).lines())
+    }
+}
+
+/// Answers a command received by the [`CommandListener`].
+pub struct Responder {
+    reply: tokio::sync::oneshot::Sender<(Reply, Option<Feed>)>,
+    written: tokio::sync::oneshot::Receiver<()>,
+}
+
+/// Events for a subscribed connection, the subscription ends when the sender's dropped
+pub type Feed = tokio::sync::mpsc::Receiver<EventRecord>;
+
+impl Responder {
+    pub fn reply(self, reply: Reply) {
+        // the caller hanging up before hearing back isn't our problem
+        let _ = self.reply.send((reply, None));
+    }
+
+    /// Replies and waits for the reply to reach the caller, for when the daemon's about to exit.
+    pub async fn reply_and_wait(self, reply: Reply) {
+        let _ = self.reply.send((reply, None));
+        let _ = self.written.await;
+    }
+
+    /// Accepts a [`Command::Subscribe`], everything from the feed is written after the reply.
+    pub fn subscribe(self, feed: Feed) {
+        let _ = self.reply.send((Reply::Ok, Some(feed)));
     }
 }
 
 pub struct CommandListener {
     shutdown: tokio::sync::oneshot::Sender<()>,
-    commands: tokio::sync::mpsc::Receiver<Command>,
+    commands: tokio::sync::mpsc::Receiver<(Command, Responder)>,
 }
 
 impl CommandListener {
@@ -83,14 +527,21 @@ impl CommandListener {
         Ok(())
     }
 
-    pub async fn next(&mut self) -> Option<Command> {
+    /// The next command, it has to be answered with the responder.
+    pub async fn next(&mut self) -> Option<(Command, Responder)> {
         self.commands.recv().await
     }
 
     // todo: Check if a daemon exists
     pub async fn start() -> Result<Self> {
-        tokio::fs::remove_file(socket_path()).await?;
-        let socket = tokio::net::UnixListener::bind(socket_path())?;
+        let socket_path = socket_path()?;
+        // left behind by a daemon that didn't get to clean up
+        match tokio::fs::remove_file(&socket_path).await {
+            Result::Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err)?,
+            _ => {}
+        }
+        let socket = tokio::net::UnixListener::bind(&socket_path)?;
+        tokio::fs::set_permissions(&socket_path, Permissions::from_mode(0o600)).await?;
         let (shutdown, mut receiver) = tokio::sync::oneshot::channel();
         let (command_sender, command_receiver) = tokio::sync::mpsc::channel(100);
         let cl = CommandListener {
@@ -104,12 +555,18 @@ impl CommandListener {
                     Result::Err(TryRecvError::Empty) => {}
                     err @ Result::Err(_) => err?,
                 };
-                let (mut stream, _) = socket.accept().await?;
-                {
-                    let mut buf = String::new();
-                    stream.read_to_string(&mut buf).await?;
-                    let command = serde_json::from_str::<Command>(&buf)?;
-                    command_sender.send(command).await?;
+                let (stream, _) = socket.accept().await?;
+                match stream.peer_cred() {
+                    Result::Ok(cred) if cred.uid() == getuid().as_raw() => {
+                        tokio::spawn(read_commands(stream, command_sender.clone()));
+                    }
+                    cred => {
+                        let message = match cred {
+                            Result::Ok(cred) => format!("uid {} can't use this daemon", cred.uid()),
+                            Result::Err(err) => format!("couldn't tell who's calling: {err}"),
+                        };
+                        tokio::spawn(refuse(stream, message));
+                    }
                 }
             }
             Ok(())
@@ -117,3 +574,144 @@ impl CommandListener {
         Ok(cl)
     }
 }
+
+/// Answers the first command from a caller we don't trust then hangs up on them.
+async fn refuse(mut stream: tokio::net::UnixStream, message: String) -> Result<()> {
+    if let Some(frame) = read_frame(&mut stream).await? {
+        let id = decode::<Command>(&frame).map_or(0, |envelope| envelope.id);
+        let kind = ErrorKind::Forbidden;
+        stream
+            .write_all(&encode(id, &Reply::Error { kind, message })?)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Fails with [`ErrorKind::Forbidden`] when the command touches a path the caller can't read and
+/// write. Paths that don't exist are left for the command to report.
+fn check_access(command: &Command) -> Option<Reply> {
+    command.paths().into_iter().find_map(|path| {
+        match access(path, AccessFlags::R_OK | AccessFlags::W_OK) {
+            Result::Err(err @ (Errno::EACCES | Errno::EPERM)) => Some(Reply::Error {
+                kind: ErrorKind::Forbidden,
+                message: format!("{path:?}: {err}"),
+            }),
+            _ => None,
+        }
+    })
+}
+
+/// Forwards every command sent over a connection until the caller hangs up, replying to each in
+/// turn.
+async fn read_commands(
+    mut stream: tokio::net::UnixStream,
+    commands: tokio::sync::mpsc::Sender<(Command, Responder)>,
+) -> Result<()> {
+    while let Some(frame) = read_frame(&mut stream).await? {
+        let (id, reply, written, feed) = match decode::<Command>(&frame) {
+            Result::Ok(Envelope { id, body }) if let Some(reply) = check_access(&body) => {
+                (id, reply, None, None)
+            }
+            Result::Ok(Envelope { id, body }) => {
+                let (responder, reply) = tokio::sync::oneshot::channel();
+                let (written, written_receiver) = tokio::sync::oneshot::channel();
+                let responder = Responder {
+                    reply: responder,
+                    written: written_receiver,
+                };
+                commands.send((body, responder)).await?;
+                let (reply, feed) = reply.await.unwrap_or_else(|_| {
+                    let kind = ErrorKind::Failed;
+                    let message = "the daemon dropped the command".to_string();
+                    (Reply::Error { kind, message }, None)
+                });
+                (id, reply, Some(written), feed)
+            }
+            Err(err) => {
+                let kind = ErrorKind::Malformed;
+                let message = err.to_string();
+                (0, Reply::Error { kind, message }, None, None)
+            }
+        };
+        stream.write_all(&encode(id, &reply)?).await?;
+        if let Some(written) = written {
+            let _ = written.send(());
+        }
+        if let Some(feed) = feed {
+            return write_feed(stream, feed).await;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a subscription's events as lines of json until the daemon ends it or the caller hangs
+/// up, the connection carries nothing else from then on.
+async fn write_feed(mut stream: tokio::net::UnixStream, mut feed: Feed) -> Result<()> {
+    while let Some(event) = feed.recv().await {
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        stream.write_all(&line).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let message = ServerMessage::Create {
+            path: "src/lib.rs".into(),
+            content: Some("fn main() {}".to_string()),
+        };
+        let frame = encode(7, &message).unwrap();
+        assert_eq!(frame[0], PROTOCOL_VERSION);
+
+        let envelope = decode::<ServerMessage>(&frame).unwrap();
+        assert_eq!(envelope.id, 7);
+        assert!(matches!(
+            envelope.body,
+            ServerMessage::Create { path, content: Some(_) } if path == Path::new("src/lib.rs")
+        ));
+    }
+
+    #[test]
+    fn test_bare_json_is_still_understood() {
+        let envelope = decode::<Command>(br#"{"Open":{"path":"/tmp/project"}}"#).unwrap();
+        assert_eq!(envelope.id, 0);
+        assert!(matches!(envelope.body, Command::Open { .. }));
+    }
+
+    #[test]
+    fn test_hello_negotiates_features() {
+        let mut hello = Hello::new("laptop", "luke", "sink");
+        hello.features = vec![Feature::Compression, Feature::Chunking];
+        assert_eq!(
+            hello.reply(&[Feature::Chunking, Feature::BinaryContent]),
+            HelloReply::Welcome {
+                version: PROTOCOL_VERSION,
+                features: vec![Feature::Chunking]
+            }
+        );
+
+        hello.version = PROTOCOL_VERSION + 1;
+        assert!(matches!(hello.reply(&[]), HelloReply::Refused { .. }));
+    }
+
+    #[test]
+    fn test_other_versions_are_refused() {
+        let mut frame = encode(
+            1,
+            &Command::Shutdown {
+                caller: "test".to_string(),
+            },
+        )
+        .unwrap();
+        frame[0] = PROTOCOL_VERSION + 1;
+        assert!(decode::<Command>(&frame).is_err());
+        frame[0] = PROTOCOL_VERSION;
+        frame.pop();
+        assert!(decode::<Command>(&frame).is_err());
+    }
+}
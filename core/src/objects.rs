@@ -1,4 +1,5 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::{
     collections::HashMap,
@@ -11,7 +12,7 @@ use tokio::io::AsyncReadExt;
 
 use crate::project::Project;
 
-#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone, Copy, Serialize, Deserialize)]
 pub struct FileObject {
     /// A file objects hash
     hash: u64,
@@ -20,16 +21,28 @@ pub struct FileObject {
 impl FileObject {
     async fn from_file<H: Hasher>(file: &mut fs::File, hasher: &mut H) -> anyhow::Result<Self> {
         let mut buf = Vec::new();
-        loop {
-            let outcome = file.read_buf(&mut buf).await?;
-            hasher.write(&buf);
-            if outcome == 0 {
-                break;
-            }
-        }
+        file.read_to_end(&mut buf).await?;
+        hasher.write(&buf);
         let hash = hasher.finish();
         Ok(Self { hash })
     }
+
+    pub fn from_bytes<H: Hasher + Default>(bytes: &[u8]) -> Self {
+        let mut hasher = H::default();
+        hasher.write(bytes);
+        Self {
+            hash: hasher.finish(),
+        }
+    }
+
+    /// Blocking read and hash of a file, for when we're outside the runtime.
+    pub fn read<H: Hasher + Default>(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::from_bytes::<H>(&std::fs::read(path)?))
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
 }
 
 #[derive(Debug)]
@@ -37,6 +50,8 @@ pub struct ObjectsDelta {
     pub added: HashMap<PathBuf, FileObject>,
     pub removed: HashMap<PathBuf, FileObject>,
     pub modified: HashMap<PathBuf, FileObject>,
+    /// Keyed by the new path, with the path it was moved from
+    pub renamed: HashMap<PathBuf, (PathBuf, FileObject)>,
 }
 
 impl ObjectsDelta {
@@ -45,11 +60,46 @@ impl ObjectsDelta {
             added: HashMap::new(),
             removed: HashMap::new(),
             modified: HashMap::new(),
+            renamed: HashMap::new(),
         }
     }
 
     pub fn is_different(&self) -> bool {
-        !self.added.is_empty() || !self.removed.is_empty() || !self.modified.is_empty()
+        !self.added.is_empty()
+            || !self.removed.is_empty()
+            || !self.modified.is_empty()
+            || !self.renamed.is_empty()
+    }
+
+    /// Pairs up removed and added files with the same content as renames. A hash has to be unique
+    /// on both sides, otherwise (think lots of empty files) we can't know which went where.
+    fn find_renames(&mut self) {
+        let mut removed_by_hash: HashMap<u64, Vec<&PathBuf>> = HashMap::new();
+        for (path, object) in &self.removed {
+            removed_by_hash.entry(object.hash).or_default().push(path);
+        }
+        let mut added_by_hash: HashMap<u64, Vec<&PathBuf>> = HashMap::new();
+        for (path, object) in &self.added {
+            added_by_hash.entry(object.hash).or_default().push(path);
+        }
+        let pairs = added_by_hash
+            .iter()
+            .filter_map(
+                |(hash, added)| match (added.as_slice(), removed_by_hash.get(hash)) {
+                    ([to], Some(removed)) if removed.len() == 1 => {
+                        Some((to.to_path_buf(), removed[0].to_path_buf()))
+                    }
+                    _ => None,
+                },
+            )
+            .collect::<Vec<_>>();
+        for (to, from) in pairs {
+            let Some(object) = self.added.remove(&to) else {
+                continue;
+            };
+            self.removed.remove(&from);
+            self.renamed.insert(to, (from, object));
+        }
     }
 
     fn add(&mut self, path: PathBuf, object: FileObject) {
@@ -70,7 +120,15 @@ pub struct Objects {
 }
 
 impl Objects {
-    pub fn patch<'a>(&mut self, diff: ObjectsDelta) -> anyhow::Result<()> {
+    /// A project's objects as they were recorded some time before, to diff against how it is now
+    pub fn recorded(root: &Path, objects: HashMap<PathBuf, FileObject>) -> Self {
+        Self {
+            project: Project::new_global_or_default(root),
+            objects,
+        }
+    }
+
+    pub fn patch(&mut self, diff: ObjectsDelta) -> anyhow::Result<()> {
         for (k, v) in diff.added {
             self.objects.insert(k.to_path_buf(), v);
         }
@@ -82,13 +140,17 @@ impl Objects {
                 *value = nv;
             }
         }
+        for (to, (from, v)) in diff.renamed {
+            self.objects.remove(&from);
+            self.objects.insert(to, v);
+        }
         Ok(())
     }
     pub async fn update<H: Hasher + Default>(
         &mut self,
         check_after: SystemTime,
     ) -> anyhow::Result<SystemTime> {
-        let mut last_time = check_after.clone();
+        let mut last_time = check_after;
         let mut found_files = HashSet::new();
         let mut directories = vec![self.project.root.clone()];
         while let Some(directory_path) = directories.pop() {
@@ -176,6 +238,62 @@ impl Objects {
                 diff.add(key.to_path_buf(), *value);
             }
         }
+        diff.find_renames();
         diff
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn objects(files: &[(&str, u64)]) -> Objects {
+        Objects {
+            project: Project::new_global_or_default(Path::new("/project")),
+            objects: files
+                .iter()
+                .map(|(path, hash)| (PathBuf::from(path), FileObject { hash: *hash }))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_diff_pairs_unique_hashes_as_renames() {
+        let before = objects(&[("a.txt", 1), ("b.txt", 2), ("empty", 0)]);
+        let after = objects(&[("c.txt", 1), ("b.txt", 2), ("new", 3)]);
+
+        let diff = before.diff(&after);
+
+        assert_eq!(
+            diff.renamed.get(Path::new("c.txt")),
+            Some(&(PathBuf::from("a.txt"), FileObject { hash: 1 }))
+        );
+        assert!(diff.added.contains_key(Path::new("new")));
+        assert!(diff.removed.contains_key(Path::new("empty")));
+        assert!(!diff.added.contains_key(Path::new("c.txt")));
+        assert!(!diff.removed.contains_key(Path::new("a.txt")));
+    }
+
+    #[test]
+    fn test_diff_ignores_ambiguous_renames() {
+        let before = objects(&[("a", 0), ("b", 0)]);
+        let after = objects(&[("c", 0), ("d", 0)]);
+
+        let diff = before.diff(&after);
+
+        assert!(diff.renamed.is_empty());
+        assert_eq!(diff.added.len(), 2);
+        assert_eq!(diff.removed.len(), 2);
+    }
+
+    #[test]
+    fn test_patch_applies_renames() {
+        let mut before = objects(&[("a.txt", 1)]);
+        let after = objects(&[("c.txt", 1)]);
+
+        let diff = before.diff(&after);
+        before.patch(diff).unwrap();
+
+        assert_eq!(before.objects, after.objects);
+    }
+}
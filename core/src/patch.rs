@@ -0,0 +1,113 @@
+use std::fmt;
+
+use anyhow::*;
+use seahash::SeaHasher;
+use serde::{Deserialize, Serialize};
+use similar::{DiffTag, TextDiff};
+
+use crate::objects::FileObject;
+
+/// Replaces a range of lines in the base with new content.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hunk {
+    /// First line of the base that's replaced, zero indexed
+    pub start: usize,
+    /// How many lines of the base are replaced, 0 for a pure insert
+    pub len: usize,
+    /// What goes in their place, line endings included
+    pub content: String,
+}
+
+/// The version id a patch is made against, the hash of the content it applies to.
+pub fn version(content: &str) -> u64 {
+    FileObject::from_bytes::<SeaHasher>(content.as_bytes()).hash()
+}
+
+/// The hunks that turn old into new, in order.
+pub fn hunks(old: &str, new: &str) -> Vec<Hunk> {
+    let diff = TextDiff::from_lines(old, new);
+    let new_lines = diff.new_slices();
+    diff.ops()
+        .iter()
+        .filter(|op| op.tag() != DiffTag::Equal)
+        .map(|op| {
+            let (old_range, new_range) = (op.old_range(), op.new_range());
+            Hunk {
+                start: old_range.start,
+                len: old_range.len(),
+                content: new_lines[new_range].concat(),
+            }
+        })
+        .collect()
+}
+
+/// The base a patch was made against isn't what the receiver has, so the patch can't be trusted
+/// and the whole file has to be sent instead.
+#[derive(Debug)]
+pub struct StaleBase {
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl fmt::Display for StaleBase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "patch is against version {:x} but the file is at {:x}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for StaleBase {}
+
+/// Applies hunks made with [`hunks`] to the content they were made against, base being its
+/// [`version`]. Errors with [`StaleBase`] when the content has moved on.
+pub fn apply(content: &str, base: u64, hunks: &[Hunk]) -> Result<String> {
+    let found = version(content);
+    if found != base {
+        return Err(StaleBase {
+            expected: base,
+            found,
+        }
+        .into());
+    }
+    let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+    let mut patched = String::with_capacity(content.len());
+    let mut line = 0;
+    for hunk in hunks {
+        if hunk.start < line || hunk.start + hunk.len > lines.len() {
+            return Err(anyhow!(
+                "hunk at line {} for {} lines doesn't fit a {} line file",
+                hunk.start,
+                hunk.len,
+                lines.len()
+            ));
+        }
+        patched.extend(lines[line..hunk.start].iter().copied());
+        patched.push_str(&hunk.content);
+        line = hunk.start + hunk.len;
+    }
+    patched.extend(lines[line..].iter().copied());
+    Ok(patched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hunks_apply_to_their_base() {
+        let old = "fn main() {\n    println!(\"hi\");\n}\n\nfn other() {}\n";
+        let new = "// entry\nfn main() {\n    println!(\"hello\");\n}\n";
+        let hunks = hunks(old, new);
+        assert_eq!(apply(old, version(old), &hunks).unwrap(), new);
+    }
+
+    #[test]
+    fn test_stale_base_is_refused() {
+        let hunks = hunks("a\nb\n", "a\nc\n");
+        let err = apply("a\nd\n", version("a\nb\n"), &hunks).unwrap_err();
+        assert!(err.is::<StaleBase>());
+    }
+}
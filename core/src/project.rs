@@ -2,6 +2,8 @@ use std::path::{Path, PathBuf};
 
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 
+use crate::config::{SINK_DIRECTORY, TEMP_SUFFIX};
+
 #[derive(Debug)]
 pub struct Project {
     pub root: PathBuf,
@@ -26,12 +28,20 @@ impl Project {
 
     pub fn new_global(root: &Path) -> anyhow::Result<Self> {
         let mut ignore_builder = GitignoreBuilder::new(root);
-        match ignore_builder.add(root.join(".gitignore")) {
-            Some(err) => eprintln!("{err:?}"),
-            None => (),
+        if let Some(err) = ignore_builder.add(root.join(".gitignore")) {
+            eprintln!("{err:?}");
         }
         ignore_builder.add_line(None, ".git")?;
-        let (matcher, _) = ignore_builder.build_global();
+        ignore_builder.add_line(None, SINK_DIRECTORY)?;
+        ignore_builder.add_line(None, &format!("*{TEMP_SUFFIX}"))?;
+        let (matcher, _) = ignore_builder.clone().build_global();
+        // without a global excludes file build_global hands back an empty matcher, dropping the
+        // project's rules along with it
+        let matcher = if matcher.is_empty() {
+            ignore_builder.build()?
+        } else {
+            matcher
+        };
 
         Ok(Self {
             root: root.to_path_buf(),
@@ -70,6 +80,32 @@ impl Project {
         }
     }
 
+    /// Walks a directory inside the project returning the relative path of every file that isn't
+    /// ignored, used when a whole directory shows up at once (created or moved in).
+    pub fn files_in(&self, directory: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut directories = vec![directory.to_path_buf()];
+        while let Some(directory_path) = directories.pop() {
+            let Result::Ok(entries) = std::fs::read_dir(&directory_path) else {
+                // removed while we were walking it, nothing left to find
+                continue;
+            };
+            for entry in entries.flatten() {
+                let absolute_path = entry.path();
+                let is_dir = absolute_path.is_dir();
+                let Some(relative_path) = self.exists(&absolute_path, is_dir) else {
+                    continue;
+                };
+                if is_dir {
+                    directories.push(absolute_path);
+                } else if absolute_path.is_file() {
+                    files.push(relative_path.to_path_buf());
+                }
+            }
+        }
+        files
+    }
+
     /// None if ignored, relative path if not ignored
     pub fn exists<'a>(&self, path: &'a Path, is_dir: bool) -> Option<&'a Path> {
         if let Result::Ok(relative_path) = path.strip_prefix(&self.root) {
@@ -90,3 +126,21 @@ impl Project {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_inside_the_sink_directory_are_ignored() {
+        let project = Project::new_global_or_default(Path::new("/project"));
+        let queue = Path::new("/project/.sink/queue.jsonl");
+        assert_eq!(project.exists_parent(queue, false), None);
+        let temp = Path::new("/project/src/.lib.rs.sink-tmp");
+        assert_eq!(project.exists_parent(temp, false), None);
+        assert_eq!(
+            project.exists_parent(Path::new("/project/src/lib.rs"), false),
+            Some(Path::new("src/lib.rs"))
+        );
+    }
+}
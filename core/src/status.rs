@@ -0,0 +1,121 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use nix::sys::{
+    resource::{UsageWho, getrusage},
+    time::TimeValLike,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::watcher::{ChangeKind, WatchStatus};
+
+/// How a project's connection to the server is doing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// Everything the daemon knows about a project it has open
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProjectStatus {
+    pub root: PathBuf,
+    pub watch: WatchStatus,
+    pub connection: ConnectionState,
+    /// Changes waiting to be sent to the server
+    pub pending: usize,
+    /// When the server last acknowledged one of our changes
+    pub last_sync: Option<SystemTime>,
+    /// Why the project last lost the server
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// When the daemon next tries to reconnect, while disconnected
+    #[serde(default)]
+    pub retry_at: Option<SystemTime>,
+}
+
+/// What the daemon's costing the machine
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub cpu_user: Duration,
+    pub cpu_system: Duration,
+    /// Resident memory in bytes, None where we can't find out
+    pub memory: Option<u64>,
+}
+
+impl Usage {
+    pub fn current() -> Self {
+        let mut usage = Usage {
+            memory: resident_memory(),
+            ..Default::default()
+        };
+        if let Ok(rusage) = getrusage(UsageWho::RUSAGE_SELF) {
+            usage.cpu_user = Duration::from_micros(rusage.user_time().num_microseconds() as u64);
+            usage.cpu_system =
+                Duration::from_micros(rusage.system_time().num_microseconds() as u64);
+        }
+        usage
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    // looks like "VmRSS:     12345 kB"
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory() -> Option<u64> {
+    None
+}
+
+/// The reply to `Status`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started: SystemTime,
+    pub projects: Vec<ProjectStatus>,
+    pub usage: Usage,
+}
+
+/// The reply to `Stats`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub uptime: Duration,
+    /// Changes the watcher has reported since the daemon started
+    pub events: u64,
+    /// Changes written into our projects from the server
+    pub remote_events: u64,
+    /// Everything the daemon logged as going wrong, failed commands and changes that couldn't be
+    /// synced alike
+    pub errors: u64,
+    pub usage: Usage,
+}
+
+/// Where a change was made
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Origin {
+    /// On this machine, seen by the watcher
+    #[default]
+    Local,
+    /// By someone else on the stream, written into the project by the daemon
+    Remote,
+}
+
+/// A change the daemon saw, the reply to `Events` is the most recent of these and subscribers
+/// get each one as it happens
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EventRecord {
+    pub root: PathBuf,
+    pub kind: ChangeKind,
+    pub at: SystemTime,
+    #[serde(default)]
+    pub origin: Origin,
+}
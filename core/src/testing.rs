@@ -0,0 +1,59 @@
+//! Helpers for tests in this crate and the ones depending on it.
+
+use std::{
+    fs::DirBuilder,
+    hash::{BuildHasher, Hasher, RandomState},
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// Runs a future to completion on a fresh current thread runtime. Anything depending on a crate
+/// named core can't expand `#[tokio::test]`, so tests drive their futures with this instead.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("a runtime for the test")
+        .block_on(future)
+}
+
+/// An empty directory of its own in the temp dir, removed along with everything in it when
+/// dropped, failed asserts included.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        loop {
+            // a fresh RandomState is randomly keyed, so runs side by side don't pick the same name
+            let suffix = RandomState::new().build_hasher().finish();
+            let path = std::env::temp_dir().join(format!("sink-{name}-{suffix:x}"));
+            match DirBuilder::new().create(&path) {
+                Ok(()) => return Self { path },
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => panic!("couldn't make {path:?}: {err}"),
+            }
+        }
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
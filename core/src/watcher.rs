@@ -2,150 +2,548 @@ use anyhow::*;
 use async_trait::async_trait;
 use futures::executor::block_on;
 use notify::{
-    RecommendedWatcher, Watcher as _,
-    event::{CreateKind, ModifyKind, RemoveKind},
+    EventKind, RecommendedWatcher, Watcher as _,
+    event::{ModifyKind, RenameMode},
 };
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
     hash::Hasher,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex},
     time::{Duration, Instant, SystemTime},
 };
 use tokio::{
+    select,
     sync::{
-        Mutex,
+        Mutex, Notify,
         mpsc::{Receiver, Sender, channel},
     },
     task::JoinHandle,
+    time::timeout,
 };
 
-use crate::{objects::Objects, path_is_child, path_is_parent, project::Project};
+use crate::{
+    config::{PollConfig, ProjectConfig},
+    objects::{FileObject, Objects, ObjectsDelta},
+    path_is_child, path_is_parent,
+    project::Project,
+};
 
-const POLL_SECONDS: u64 = 1;
+/// How often the hybrid watcher rescans a root to catch anything notify missed.
+const VERIFY_SECONDS: u64 = 60;
+/// How long a write the daemon made itself is swallowed for.
+const ECHO_SECONDS: u64 = 60;
+/// How long the `From` half of a rename waits for its `To` before it's taken as moved out of
+/// everything we watch.
+const RENAME_MILLIS: u64 = 500;
 
-#[derive(Debug)]
-pub enum ChangeEvent {
+/// Paths are relative to the root of the project the change happened in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
     Modified(PathBuf),
     Created(PathBuf),
     Deleted(PathBuf),
+    /// A file was moved within the same project
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+impl ChangeKind {
+    /// Every path the change touches, for a rename that's both where it came from and went to.
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            ChangeKind::Modified(path) | ChangeKind::Created(path) | ChangeKind::Deleted(path) => {
+                vec![path]
+            }
+            ChangeKind::Renamed { from, to } => vec![from, to],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+    /// Root of the project the change belongs to
+    pub root: PathBuf,
+    pub kind: ChangeKind,
+    /// The file as it is now, None for deletes or when the watcher couldn't read it
+    pub object: Option<FileObject>,
+    /// When the watcher observed the change
+    pub at: SystemTime,
+}
+
+impl ChangeEvent {
+    pub fn new(root: &Path, kind: ChangeKind, object: Option<FileObject>) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            kind,
+            object,
+            at: SystemTime::now(),
+        }
+    }
+
+    pub fn paths(&self) -> Vec<&Path> {
+        self.kind.paths()
+    }
+}
+
+/// How a watched root is getting on
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WatchStatus {
+    /// When the root was last scanned, None until a scan has finished (or if it's never scanned)
+    pub last_scan: Option<SystemTime>,
+    /// Files under the root, None if the watcher doesn't keep track
+    pub files: Option<usize>,
+    /// The last thing that went wrong watching the root
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WatchedRoot {
+    pub root: PathBuf,
+    pub status: WatchStatus,
+}
+
+/// Why a root couldn't be watched or unwatched
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchError {
+    AlreadyWatched(PathBuf),
+    /// The root is inside one that's already being watched
+    InsideWatched(PathBuf),
+    NotWatched(PathBuf),
 }
 
-// todo: Let's add a method for "currently watched"
+impl std::fmt::Display for WatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchError::AlreadyWatched(path) => write!(f, "{path:?} is already being watched"),
+            WatchError::InsideWatched(path) => {
+                write!(f, "{path:?} is a child of another watched path")
+            }
+            WatchError::NotWatched(path) => write!(f, "{path:?} is not being watched"),
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
 #[async_trait]
 pub trait Watcher {
     async fn watch<H: Hasher + Default + Send>(&mut self, path: &Path) -> Result<()>;
     async fn unwatch(&mut self, path: &Path) -> Result<()>;
     async fn recv(&mut self) -> Option<ChangeEvent>;
+    /// Every root currently being watched
+    async fn watched(&self) -> Vec<WatchedRoot>;
+    /// Registers a write the daemon is about to make itself so the events it causes aren't
+    /// reported. The object is what the file at the absolute path will hash to, None for a delete.
+    fn expect_write(&mut self, path: &Path, object: Option<FileObject>);
+    /// Drops what [`Watcher::expect_write`] registered for a write that never happened, so the
+    /// next change to the path is reported.
+    fn forget_write(&mut self, path: &Path);
+}
+
+/// Writes the daemon made itself, these would otherwise come straight back out of the watcher and
+/// get sent back to where they came from.
+#[derive(Default)]
+struct Echoes {
+    expected: HashMap<PathBuf, (Option<FileObject>, Instant)>,
+}
+
+impl Echoes {
+    fn expect(&mut self, path: &Path, object: Option<FileObject>) {
+        self.expected
+            .insert(path.to_path_buf(), (object, Instant::now()));
+    }
+
+    /// True when the event is one of our own writes. A write can cause several events so
+    /// registrations are kept until they expire, an event that doesn't match what we wrote is
+    /// someone else's edit and gets through. That drops the registration too, the file's theirs
+    /// now and putting back what we wrote is an edit of its own.
+    fn is_echo(&mut self, event: &ChangeEvent) -> bool {
+        self.expected
+            .retain(|_, (_, at)| at.elapsed() < Duration::from_secs(ECHO_SECONDS));
+        let root = &event.root;
+        let touched = match &event.kind {
+            ChangeKind::Created(path) | ChangeKind::Modified(path) => {
+                vec![(root.join(path), event.object)]
+            }
+            ChangeKind::Deleted(path) => vec![(root.join(path), None)],
+            ChangeKind::Renamed { from, to } => {
+                vec![(root.join(from), None), (root.join(to), event.object)]
+            }
+        };
+        let mut echo = true;
+        for (path, object) in touched {
+            if !self.matches(&path, object) {
+                self.expected.remove(&path);
+                echo = false;
+            }
+        }
+        echo
+    }
+
+    fn forget(&mut self, path: &Path) {
+        self.expected.remove(path);
+    }
+
+    fn matches(&self, path: &Path, object: Option<FileObject>) -> bool {
+        self.expected
+            .get(path)
+            .is_some_and(|(expected, _)| *expected == object)
+    }
+}
+
+/// Sends the events for a delta found under root, skipping any paths that have already been
+/// reported.
+async fn send_delta(
+    sender: &Sender<ChangeEvent>,
+    root: &Path,
+    delta: &ObjectsDelta,
+    skip: &HashSet<PathBuf>,
+) -> Result<()> {
+    for (key, object) in delta.added.iter().filter(|(key, _)| !skip.contains(*key)) {
+        let kind = ChangeKind::Created(key.to_path_buf());
+        sender
+            .send(ChangeEvent::new(root, kind, Some(*object)))
+            .await?;
+    }
+    for key in delta.removed.keys().filter(|key| !skip.contains(*key)) {
+        let kind = ChangeKind::Deleted(key.to_path_buf());
+        sender.send(ChangeEvent::new(root, kind, None)).await?;
+    }
+    for (key, object) in delta
+        .modified
+        .iter()
+        .filter(|(key, _)| !skip.contains(*key))
+    {
+        let kind = ChangeKind::Modified(key.to_path_buf());
+        sender
+            .send(ChangeEvent::new(root, kind, Some(*object)))
+            .await?;
+    }
+    for (to, (from, object)) in &delta.renamed {
+        if skip.contains(to) || skip.contains(from) {
+            continue;
+        }
+        let kind = ChangeKind::Renamed {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        };
+        sender
+            .send(ChangeEvent::new(root, kind, Some(*object)))
+            .await?;
+    }
+    Ok(())
+}
+
+/// A project watched by notify, notify only gives us paths so we hold on to how the project's
+/// files should be hashed.
+struct NotifyProject {
+    project: Project,
+    read: fn(&Path) -> std::io::Result<FileObject>,
+    error: Option<String>,
+}
+
+/// Finds the project a notify path belongs to, returning the project root and the path relative
+/// to it. None if the path is outside every project or ignored.
+fn find_project<'a>(
+    projects: &'a HashMap<PathBuf, NotifyProject>,
+    path: &Path,
+) -> Option<(&'a NotifyProject, PathBuf)> {
+    projects
+        .iter()
+        .filter(|(root, _)| path.starts_with(root))
+        .find_map(|(_, watched)| {
+            watched
+                .project
+                .exists_parent(path, path.is_dir())
+                .map(|relative_path| (watched, relative_path.to_path_buf()))
+        })
+}
+
+/// Turns raw notify events into change events. Directory events are expanded into an event per
+/// file by scanning the affected subtree, and the two halves of a rename are paired back up.
+struct NotifyHandler {
+    events: Vec<ChangeEvent>,
+    /// The `From` half of a rename that's waiting on its `To`, and when it arrived
+    rename_from: Option<(Option<usize>, PathBuf, Instant)>,
+    /// The tracker of the last rename we paired, inotify follows up with a `Both` for the same move
+    paired: Option<usize>,
+    /// The files under each root as far as we've heard. A directory's already gone by the time
+    /// it's reported removed, this is how we know what was in it.
+    files: HashMap<PathBuf, HashSet<PathBuf>>,
+}
+
+impl NotifyHandler {
+    fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            rename_from: None,
+            paired: None,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Starts keeping track of the files under a newly watched root.
+    fn track(&mut self, root: &Path, files: Vec<PathBuf>) {
+        self.files
+            .insert(root.to_path_buf(), files.into_iter().collect());
+    }
+
+    fn untrack(&mut self, root: &Path) {
+        self.files.remove(root);
+    }
+
+    /// Handles a notify event, returning the change events found for it.
+    fn handle(
+        &mut self,
+        projects: &HashMap<PathBuf, NotifyProject>,
+        event: notify::Event,
+    ) -> Vec<ChangeEvent> {
+        let tracker = event.attrs.tracker();
+        let is_rename_to = matches!(
+            event.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+        if !is_rename_to && let Some((_, from, _)) = self.rename_from.take() {
+            // a rename that never arrived anywhere we watch was moved out of the project
+            self.removed(projects, &from);
+        }
+        match event.kind {
+            EventKind::Create(_) => {
+                for path in &event.paths {
+                    self.created(projects, path);
+                }
+            }
+            EventKind::Modify(ModifyKind::Data(_)) => {
+                for path in event.paths.iter().filter(|path| path.is_file()) {
+                    if let Some((watched, relative_path)) = find_project(projects, path) {
+                        self.push(watched, ChangeKind::Modified(relative_path));
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                if let Some(path) = event.paths.into_iter().next() {
+                    self.rename_from = Some((tracker, path, Instant::now()));
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                let Some(to) = event.paths.first() else {
+                    return self.take();
+                };
+                match self.rename_from.take() {
+                    Some((from_tracker, from, _)) if from_tracker == tracker => {
+                        self.paired = tracker;
+                        self.renamed(projects, &from, to);
+                    }
+                    Some((_, from, _)) => {
+                        self.removed(projects, &from);
+                        self.created(projects, to);
+                    }
+                    // moved in from somewhere we don't watch
+                    None => self.created(projects, to),
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                let already_paired = tracker.is_some() && tracker == self.paired;
+                if let [from, to] = event.paths.as_slice()
+                    && !already_paired
+                {
+                    self.renamed(projects, from, to);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                // the platform can't tell us which side of the rename this is, so look
+                for path in &event.paths {
+                    if path.exists() {
+                        self.created(projects, path);
+                    } else {
+                        self.removed(projects, path);
+                    }
+                }
+            }
+            EventKind::Remove(_) => {
+                for path in &event.paths {
+                    self.removed(projects, path);
+                }
+            }
+            _ => (),
+        };
+        self.take()
+    }
+
+    /// A rename that's waited longer than `after` for its `To` was moved out of the project,
+    /// e.g. to the trash. Returns its delete, if there was one.
+    fn expire(
+        &mut self,
+        projects: &HashMap<PathBuf, NotifyProject>,
+        after: Duration,
+    ) -> Vec<ChangeEvent> {
+        if let Some((_, from, at)) = &self.rename_from
+            && at.elapsed() >= after
+        {
+            let from = from.clone();
+            self.rename_from = None;
+            self.removed(projects, &from);
+        }
+        self.take()
+    }
+
+    fn take(&mut self) -> Vec<ChangeEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Records a change, hashing whatever the change left behind.
+    fn push(&mut self, watched: &NotifyProject, kind: ChangeKind) {
+        let root = &watched.project.root;
+        if let Some(files) = self.files.get_mut(root) {
+            match &kind {
+                ChangeKind::Created(path) | ChangeKind::Modified(path) => {
+                    files.insert(path.clone());
+                }
+                ChangeKind::Deleted(path) => {
+                    files.remove(path);
+                }
+                ChangeKind::Renamed { from, to } => {
+                    files.remove(from);
+                    files.insert(to.clone());
+                }
+            }
+        }
+        let object = match &kind {
+            ChangeKind::Deleted(_) => None,
+            ChangeKind::Modified(path)
+            | ChangeKind::Created(path)
+            | ChangeKind::Renamed { to: path, .. } => (watched.read)(&root.join(path)).ok(),
+        };
+        self.events.push(ChangeEvent::new(root, kind, object));
+    }
+
+    /// A file or a whole directory appeared at path.
+    fn created(&mut self, projects: &HashMap<PathBuf, NotifyProject>, path: &Path) {
+        let Some((watched, relative_path)) = find_project(projects, path) else {
+            return;
+        };
+        if path.is_dir() {
+            for file in watched.project.files_in(path) {
+                self.push(watched, ChangeKind::Created(file));
+            }
+        } else if path.is_file() {
+            self.push(watched, ChangeKind::Created(relative_path));
+        }
+    }
+
+    /// A file or directory was removed from path. A directory's already gone, each file we knew
+    /// was in it is reported deleted.
+    fn removed(&mut self, projects: &HashMap<PathBuf, NotifyProject>, path: &Path) {
+        let Some((watched, relative_path)) = find_project(projects, path) else {
+            return;
+        };
+        let mut inside = self
+            .files
+            .get(&watched.project.root)
+            .map(|files| {
+                files
+                    .iter()
+                    .filter(|file| file.starts_with(&relative_path) && **file != relative_path)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if inside.is_empty() {
+            return self.push(watched, ChangeKind::Deleted(relative_path));
+        }
+        inside.sort();
+        for file in inside {
+            self.push(watched, ChangeKind::Deleted(file));
+        }
+    }
+
+    /// A file or directory was moved from one path to another.
+    fn renamed(&mut self, projects: &HashMap<PathBuf, NotifyProject>, from: &Path, to: &Path) {
+        let Some((to_project, to_relative)) = find_project(projects, to) else {
+            // moved out of everything we watch
+            return self.removed(projects, from);
+        };
+        let Some((from_project, from_relative)) = find_project(projects, from) else {
+            // moved in from somewhere we don't watch
+            return self.created(projects, to);
+        };
+        let moves = if to.is_dir() {
+            to_project
+                .project
+                .files_in(to)
+                .into_iter()
+                .filter_map(|file| {
+                    let inner = file.strip_prefix(&to_relative).ok()?;
+                    Some((from_relative.join(inner), file))
+                })
+                .collect::<Vec<_>>()
+        } else {
+            vec![(from_relative, to_relative)]
+        };
+        for (from, to) in moves {
+            if from_project.project.root == to_project.project.root {
+                self.push(to_project, ChangeKind::Renamed { from, to });
+            } else {
+                // each project only knows about its own files
+                self.push(from_project, ChangeKind::Deleted(from));
+                self.push(to_project, ChangeKind::Created(to));
+            }
+        }
+    }
 }
 
 pub struct NotifyWatcher {
     receiver: Receiver<ChangeEvent>,
     watcher: RecommendedWatcher,
-    projects: Arc<Mutex<HashMap<PathBuf, Project>>>,
+    projects: Arc<Mutex<HashMap<PathBuf, NotifyProject>>>,
+    /// Shared with notify's callback, recv flushes renames that never found their other half
+    handler: Arc<StdMutex<NotifyHandler>>,
+    /// Deletes from expired renames waiting to be received
+    expired: VecDeque<ChangeEvent>,
+    echoes: Echoes,
 }
 
-// todo: NotifyWatcher need's to
-impl NotifyWatcher {
-    pub async fn paths_vec(&self) -> Vec<PathBuf> {
-        self.projects
-            .lock()
-            .await
-            .keys()
-            .map(|path| path.clone())
-            .collect::<Vec<_>>()
+impl Default for NotifyWatcher {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
+// todo: NotifyWatcher need's to
+impl NotifyWatcher {
     pub fn new() -> Self {
         let (tx, rx) = tokio::sync::mpsc::channel::<ChangeEvent>(1000);
-        let project_ls: HashMap<PathBuf, Project> = HashMap::new();
+        let project_ls: HashMap<PathBuf, NotifyProject> = HashMap::new();
         let projects = Arc::new(Mutex::new(project_ls));
         let projects_clone = projects.clone();
+        let handler = Arc::new(StdMutex::new(NotifyHandler::new()));
+        let handler_clone = handler.clone();
         let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
             block_on(async {
-                let mut event = res.unwrap();
-                let projects = projects_clone.lock().await;
-                match event.kind {
-                    notify::EventKind::Create(CreateKind::File) => {
-                        for path in event.paths {
-                            for (root, project) in projects.iter() {
-                                if path.starts_with(root) {
-                                    if let Some(relative_path) =
-                                        project.exists(&path, path.is_dir())
-                                    {
-                                        tx.send(ChangeEvent::Created(relative_path.to_path_buf()))
-                                            .await
-                                            .unwrap();
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    notify::EventKind::Modify(ModifyKind::Name(notify::event::RenameMode::To)) => {
-                        if let Some(path) = event.paths.pop() {
-                            for (root, project) in projects.iter() {
-                                if path.starts_with(root) {
-                                    if let Some(relative_path) =
-                                        project.exists(&path, path.is_dir())
-                                    {
-                                        tx.send(ChangeEvent::Deleted(relative_path.to_path_buf()))
-                                            .await
-                                            .unwrap();
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        if let Some(path) = event.paths.pop() {
-                            for (root, project) in projects.iter() {
-                                if path.starts_with(root) {
-                                    if let Some(relative_path) =
-                                        project.exists(&path, path.is_dir())
-                                    {
-                                        tx.send(ChangeEvent::Created(relative_path.to_path_buf()))
-                                            .await
-                                            .unwrap();
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    notify::EventKind::Modify(ModifyKind::Data(_)) => {
-                        for path in event.paths {
-                            for (root, project) in projects.iter() {
-                                if path.starts_with(root) {
-                                    if let Some(relative_path) =
-                                        project.exists(&path, path.is_dir())
-                                    {
-                                        tx.send(ChangeEvent::Modified(relative_path.to_path_buf()))
-                                            .await
-                                            .unwrap();
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    notify::EventKind::Remove(RemoveKind::File) => {
-                        for path in event.paths {
-                            for (root, project) in projects.iter() {
-                                if path.starts_with(root) {
-                                    if let Some(relative_path) =
-                                        project.exists(&path, path.is_dir())
-                                    {
-                                        tx.send(ChangeEvent::Deleted(relative_path.to_path_buf()))
-                                            .await
-                                            .unwrap();
-                                        break;
-                                    }
-                                }
+                let mut projects = projects_clone.lock().await;
+                let event = match res {
+                    Result::Ok(event) => event,
+                    Result::Err(err) => {
+                        // an error without paths is about the whole watcher
+                        for (root, watched) in projects.iter_mut() {
+                            if err.paths.is_empty() || err.paths.iter().any(|p| p.starts_with(root))
+                            {
+                                watched.error = Some(err.to_string());
                             }
                         }
+                        return;
                     }
-                    _ => (),
                 };
+                let events = handler_clone.lock().unwrap().handle(&projects, event);
+                drop(projects);
+                for event in events {
+                    tx.send(event).await.unwrap();
+                }
             })
         })
         .unwrap();
@@ -153,6 +551,9 @@ impl NotifyWatcher {
             receiver: rx,
             watcher,
             projects: projects.clone(),
+            handler,
+            expired: VecDeque::new(),
+            echoes: Echoes::default(),
         }
     }
 }
@@ -161,39 +562,170 @@ impl NotifyWatcher {
 impl Watcher for NotifyWatcher {
     async fn watch<H: Hasher + Default + Send>(&mut self, path: &Path) -> Result<()> {
         let mut do_not_continue = false;
-        for path_buf in self.paths_vec().await {
-            if path_is_child(&path, &path_buf) {
+        for watched in self.watched().await {
+            if path_is_child(path, &watched.root) {
                 // the new path is a child we simply ignore the add.
                 do_not_continue = true;
-            } else if path_is_parent(&path, &path_buf) {
+            } else if path_is_parent(path, &watched.root) {
                 // if our new watch is above any of our current watched paths, unwatch.
-                self.unwatch(&path_buf).await?;
+                self.unwatch(&watched.root).await?;
             }
         }
         if do_not_continue {
             return Ok(());
         }
+        let watched = NotifyProject {
+            project: Project::new_global_or_default(path),
+            read: FileObject::read::<H>,
+            error: None,
+        };
+        let files = watched.project.files_in(path);
+        self.handler.lock().unwrap().track(path, files);
         self.projects
             .lock()
             .await
-            .insert(path.to_path_buf(), Project::new_global_or_default(path));
+            .insert(path.to_path_buf(), watched);
         self.watcher.watch(path, notify::RecursiveMode::Recursive)?;
         Ok(())
     }
     async fn unwatch(&mut self, path: &Path) -> Result<()> {
         self.projects.lock().await.remove(path);
+        self.handler.lock().unwrap().untrack(path);
         self.watcher.unwatch(path)?;
         Ok(())
     }
     async fn recv(&mut self) -> Option<ChangeEvent> {
-        self.receiver.recv().await
+        let wait = Duration::from_millis(RENAME_MILLIS);
+        loop {
+            let event = match self.expired.pop_front() {
+                Some(event) => event,
+                // notify only calls back with events, so a rename waiting on its other half is
+                // checked on here whenever it's been quiet for a while
+                None => match timeout(wait, self.receiver.recv()).await {
+                    Result::Ok(event) => event?,
+                    Err(_) => {
+                        let projects = self.projects.lock().await;
+                        let expired = self.handler.lock().unwrap().expire(&projects, wait);
+                        self.expired.extend(expired);
+                        continue;
+                    }
+                },
+            };
+            if !self.echoes.is_echo(&event) {
+                return Some(event);
+            }
+        }
+    }
+    fn expect_write(&mut self, path: &Path, object: Option<FileObject>) {
+        self.echoes.expect(path, object);
+    }
+
+    fn forget_write(&mut self, path: &Path) {
+        self.echoes.forget(path);
+    }
+    async fn watched(&self) -> Vec<WatchedRoot> {
+        self.projects
+            .lock()
+            .await
+            .iter()
+            .map(|(root, watched)| WatchedRoot {
+                root: root.clone(),
+                status: WatchStatus {
+                    error: watched.error.clone(),
+                    ..WatchStatus::default()
+                },
+            })
+            .collect()
     }
 }
 
+/// Works out how long the polling watcher waits between scans. Scans are kept inside the
+/// project's cpu budget, a busy project is scanned as often as the budget allows and an idle one
+/// backs off.
+struct PollSchedule {
+    config: PollConfig,
+    interval: Duration,
+}
+
+impl PollSchedule {
+    fn new(config: PollConfig) -> Self {
+        let interval = config.min_interval();
+        Self { config, interval }
+    }
+
+    /// The wait before the next scan, given what the last scan cost and if it found any changes.
+    fn next(&mut self, cost: Duration, changed: bool) -> Duration {
+        let budget = self.config.cpu_budget.clamp(0.001, 1.0);
+        // scanning for `cost` then waiting keeps us on budget when cost / (cost + wait) = budget
+        let floor = cost
+            .mul_f64((1.0 - budget) / budget)
+            .max(self.config.min_interval());
+        self.interval = if changed {
+            floor
+        } else {
+            (self.interval * 2)
+                .min(self.config.max_interval())
+                .max(floor)
+        };
+        self.interval
+    }
+}
+
+/// Runs a root's scan loop, keeping whatever stopped it in the root's status.
+async fn scan_until_error(
+    status: Arc<Mutex<WatchStatus>>,
+    scan: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    let result = scan.await;
+    if let Result::Err(err) = &result {
+        status.lock().await.error = Some(format!("{err:#}"));
+    }
+    result
+}
+
+async fn scanned(status: &Mutex<WatchStatus>, objects: &Objects) {
+    let mut status = status.lock().await;
+    status.last_scan = Some(SystemTime::now());
+    status.files = Some(objects.objects.len());
+}
+
+/// Polls root for changes until it's unwatched.
+async fn poll<H: Hasher + Default>(
+    root: PathBuf,
+    sender: Sender<ChangeEvent>,
+    status: Arc<Mutex<WatchStatus>>,
+) -> Result<()> {
+    let config = ProjectConfig::load(&root).await?;
+    let mut schedule = PollSchedule::new(config.poll);
+    let mut before = Objects::from_directory::<H>(&root).await?;
+    let mut after = Objects::from_directory::<H>(&root).await?;
+    let mut start_at_sys = SystemTime::now();
+    scanned(&status, &before).await;
+    loop {
+        let scan_start = Instant::now();
+        start_at_sys = after.update::<H>(start_at_sys).await?;
+        let diff = before.diff(&after);
+        let cost = scan_start.elapsed();
+        let changed = diff.is_different();
+        send_delta(&sender, &root, &diff, &HashSet::new()).await?;
+        before.patch(diff)?;
+        scanned(&status, &before).await;
+        tokio::time::sleep(schedule.next(cost, changed)).await;
+    }
+}
+
+/// A root with a scanning task of its own.
+struct Scanner {
+    handle: JoinHandle<Result<()>>,
+    status: Arc<Mutex<WatchStatus>>,
+}
+
+/// Polls every root it watches on a task of its own, sending all their events down one channel.
 pub struct AsyncWatcher {
-    watching: HashMap<PathBuf, JoinHandle<Result<()>>>,
+    watching: HashMap<PathBuf, Scanner>,
     sender: Sender<ChangeEvent>,
     receiver: Receiver<ChangeEvent>,
+    echoes: Echoes,
 }
 
 impl AsyncWatcher {
@@ -203,84 +735,708 @@ impl AsyncWatcher {
             watching: HashMap::new(),
             sender,
             receiver,
+            echoes: Echoes::default(),
         })
     }
-    pub fn paths_vec(&self) -> Vec<PathBuf> {
-        self.watching
-            .keys()
-            .map(|path| path.clone())
-            .collect::<Vec<_>>()
-    }
 }
 
 #[async_trait]
 impl Watcher for AsyncWatcher {
     async fn recv(&mut self) -> Option<ChangeEvent> {
-        self.receiver.recv().await
+        loop {
+            let event = self.receiver.recv().await?;
+            if !self.echoes.is_echo(&event) {
+                return Some(event);
+            }
+        }
     }
 
-    async fn unwatch(&mut self, path: &Path) -> Result<()> {
-        if let Some(joiner) = self.watching.get_mut(path) {
-            joiner.abort();
-            let _ = joiner.await;
-        } else {
-            return Result::Err(anyhow!("{path:?} is not being watched"));
+    fn expect_write(&mut self, path: &Path, object: Option<FileObject>) {
+        self.echoes.expect(path, object);
+    }
+
+    fn forget_write(&mut self, path: &Path) {
+        self.echoes.forget(path);
+    }
+
+    async fn watched(&self) -> Vec<WatchedRoot> {
+        let mut watched = Vec::new();
+        for (root, scanner) in &self.watching {
+            watched.push(WatchedRoot {
+                root: root.clone(),
+                status: scanner.status.lock().await.clone(),
+            });
         }
-        self.watching.remove(path);
+        watched
+    }
+
+    async fn unwatch(&mut self, path: &Path) -> Result<()> {
+        let Some(scanner) = self.watching.remove(path) else {
+            return Result::Err(WatchError::NotWatched(path.to_path_buf()).into());
+        };
+        scanner.handle.abort();
+        let _ = scanner.handle.await;
         Ok(())
     }
 
-    /// todo: Allow for multiple paths to be watched by this watcher at the same time,
-    /// we basically will setup a task poller for each directory and send the events to our channel
     async fn watch<H: Hasher + Default + Send>(&mut self, path: &Path) -> Result<()> {
         if self.watching.contains_key(&path.to_path_buf()) {
-            return Result::Err(anyhow!("{path:?} is already being watched"));
+            return Result::Err(WatchError::AlreadyWatched(path.to_path_buf()).into());
         }
         let mut do_not_continue = false;
-        for path_buf in self.paths_vec() {
-            if path_is_child(&path, &path_buf) {
+        let roots = self.watching.keys().cloned().collect::<Vec<_>>();
+        for path_buf in roots {
+            if path_is_child(path, &path_buf) {
                 // the new path is a child we simply ignore the add.
                 do_not_continue = true;
-            } else if path_is_parent(&path, &path_buf) {
+            } else if path_is_parent(path, &path_buf) {
                 // if our new watch is above any of our current watched paths, unwatch.
                 self.unwatch(&path_buf).await?;
             }
         }
         if do_not_continue {
-            return Result::Err(anyhow!("{path:?} is a child of another watched path"));
+            return Result::Err(WatchError::InsideWatched(path.to_path_buf()).into());
         }
-        let sender = self.sender.clone();
-        let path_buf = path.to_path_buf();
-        // a arc mutex might be more efficient, but MutexGuards are weird to work with
+        let status = Arc::new(Mutex::new(WatchStatus::default()));
+        let (root, sender, scan_status) = (path.to_path_buf(), self.sender.clone(), status.clone());
         let handle = tokio::spawn(async move {
-            let mut before = Objects::from_directory::<H>(&path_buf).await?;
-            let mut after = Objects::from_directory::<H>(&path_buf).await?;
-            let mut start_at_sys = SystemTime::now();
-            loop {
-                let update_start = Instant::now();
-                start_at_sys = after.update::<H>(start_at_sys).await?;
-                let update_end = Instant::now();
-                println!("time to update: {:?}", update_end - update_start);
-                tokio::time::sleep(Duration::from_secs(POLL_SECONDS)).await;
-                let start_at = Instant::now();
-                let diff = before.diff(&after);
-                for (key, _) in &diff.added {
-                    sender.send(ChangeEvent::Created(key.to_path_buf())).await?;
-                }
-                for (key, _) in &diff.removed {
-                    sender.send(ChangeEvent::Deleted(key.to_path_buf())).await?;
-                }
-                for (key, _) in &diff.modified {
-                    sender
-                        .send(ChangeEvent::Modified(key.to_path_buf()))
-                        .await?;
-                }
-                before.patch(diff)?;
-                let end_at = Instant::now();
-                println!("time taken to poll: {:?}", end_at - start_at);
+            let scan = poll::<H>(root, sender, scan_status.clone());
+            scan_until_error(scan_status, scan).await
+        });
+        self.watching
+            .insert(path.to_path_buf(), Scanner { handle, status });
+        Ok(())
+    }
+}
+
+/// Rescans root every so often, reporting anything notify hasn't.
+async fn verify<H: Hasher + Default>(
+    root: PathBuf,
+    sender: Sender<ChangeEvent>,
+    seen: Arc<StdMutex<HashSet<PathBuf>>>,
+    status: Arc<Mutex<WatchStatus>>,
+) -> Result<()> {
+    let mut before = Objects::from_directory::<H>(&root).await?;
+    let mut after = Objects::from_directory::<H>(&root).await?;
+    let mut start_at_sys = SystemTime::now();
+    scanned(&status, &before).await;
+    loop {
+        tokio::time::sleep(Duration::from_secs(VERIFY_SECONDS)).await;
+        start_at_sys = after.update::<H>(start_at_sys).await?;
+        let diff = before.diff(&after);
+        // anything notify already told us about only needs to be brought up to date
+        let seen = std::mem::take(&mut *seen.lock().unwrap());
+        send_delta(&sender, &root, &diff, &seen).await?;
+        before.patch(diff)?;
+        scanned(&status, &before).await;
+    }
+}
+
+/// A root being verified by the hybrid watcher's background scan.
+struct Verifier {
+    scanner: Scanner,
+    /// Paths notify has reported since the last verification scan
+    seen: Arc<StdMutex<HashSet<PathBuf>>>,
+}
+
+/// Relies on notify for low latency events, with a low frequency incremental scan of every root
+/// in the background that reports anything notify missed (network mounts, suspend/resume etc).
+pub struct HybridWatcher {
+    notify: NotifyWatcher,
+    verifying: HashMap<PathBuf, Verifier>,
+    sender: Sender<ChangeEvent>,
+    receiver: Receiver<ChangeEvent>,
+    echoes: Echoes,
+}
+
+impl Default for HybridWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HybridWatcher {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel(1000);
+        Self {
+            notify: NotifyWatcher::new(),
+            verifying: HashMap::new(),
+            sender,
+            receiver,
+            echoes: Echoes::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Watcher for HybridWatcher {
+    async fn watch<H: Hasher + Default + Send>(&mut self, path: &Path) -> Result<()> {
+        if self.verifying.contains_key(path) {
+            return Result::Err(WatchError::AlreadyWatched(path.to_path_buf()).into());
+        }
+        let roots = self.verifying.keys().cloned().collect::<Vec<_>>();
+        for root in roots {
+            if path_is_child(path, &root) {
+                return Result::Err(WatchError::InsideWatched(path.to_path_buf()).into());
+            } else if path_is_parent(path, &root) {
+                // if our new watch is above any of our current watched paths, unwatch.
+                self.unwatch(&root).await?;
             }
+        }
+        self.notify.watch::<H>(path).await?;
+        let seen = Arc::new(StdMutex::new(HashSet::new()));
+        let status = Arc::new(Mutex::new(WatchStatus::default()));
+        let (root, sender, scan_seen, scan_status) = (
+            path.to_path_buf(),
+            self.sender.clone(),
+            seen.clone(),
+            status.clone(),
+        );
+        let handle = tokio::spawn(async move {
+            let scan = verify::<H>(root, sender, scan_seen, scan_status.clone());
+            scan_until_error(scan_status, scan).await
         });
-        self.watching.insert(path.to_path_buf(), handle);
+        let scanner = Scanner { handle, status };
+        self.verifying
+            .insert(path.to_path_buf(), Verifier { scanner, seen });
         Ok(())
     }
+
+    async fn unwatch(&mut self, path: &Path) -> Result<()> {
+        let Some(verifier) = self.verifying.remove(path) else {
+            return Result::Err(WatchError::NotWatched(path.to_path_buf()).into());
+        };
+        verifier.scanner.handle.abort();
+        let _ = verifier.scanner.handle.await;
+        self.notify.unwatch(path).await
+    }
+
+    async fn watched(&self) -> Vec<WatchedRoot> {
+        let notify = self.notify.watched().await;
+        let mut watched = Vec::new();
+        for (root, verifier) in &self.verifying {
+            let mut status = verifier.scanner.status.lock().await.clone();
+            if status.error.is_none() {
+                status.error = notify
+                    .iter()
+                    .find(|watched| &watched.root == root)
+                    .and_then(|watched| watched.status.error.clone());
+            }
+            watched.push(WatchedRoot {
+                root: root.clone(),
+                status,
+            });
+        }
+        watched
+    }
+
+    async fn recv(&mut self) -> Option<ChangeEvent> {
+        loop {
+            let event = select! {
+                Some(event) = self.notify.recv() => {
+                    if let Some(verifier) = self.verifying.get(&event.root) {
+                        // no awaiting once the event's been taken, a cancelled recv would lose it
+                        let mut seen = verifier.seen.lock().unwrap();
+                        seen.extend(event.paths().into_iter().map(Path::to_path_buf));
+                    }
+                    event
+                },
+                Some(event) = self.receiver.recv() => event,
+                else => return None,
+            };
+            if !self.echoes.is_echo(&event) {
+                return Some(event);
+            }
+        }
+    }
+
+    fn expect_write(&mut self, path: &Path, object: Option<FileObject>) {
+        self.echoes.expect(path, object);
+    }
+
+    fn forget_write(&mut self, path: &Path) {
+        self.echoes.forget(path);
+    }
+}
+
+/// A call made on a [`MockWatcher`], recorded so tests can assert on them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchCall {
+    Watch(PathBuf),
+    Unwatch(PathBuf),
+    ExpectWrite(PathBuf, Option<FileObject>),
+    ForgetWrite(PathBuf),
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Virtual time since the watcher was made, only moves when a test advances it
+    elapsed: Duration,
+    /// Events waiting for the clock to reach them, kept in the order they're due
+    scheduled: Vec<(Duration, ChangeEvent)>,
+    ready: VecDeque<ChangeEvent>,
+    calls: Vec<WatchCall>,
+    watching: Vec<PathBuf>,
+    closed: bool,
+}
+
+impl MockState {
+    fn now(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + self.elapsed
+    }
+
+    fn release_due(&mut self) {
+        let due = self
+            .scheduled
+            .iter()
+            .take_while(|(at, _)| *at <= self.elapsed)
+            .count();
+        for (at, mut event) in self.scheduled.drain(..due) {
+            event.at = SystemTime::UNIX_EPOCH + at;
+            self.ready.push_back(event);
+        }
+    }
+}
+
+/// An in memory watcher that never touches the filesystem, tests drive it through the
+/// [`MockWatcherHandle`] it's made with. Time is virtual so nothing ever has to sleep.
+pub struct MockWatcher {
+    state: Arc<StdMutex<MockState>>,
+    wake: Arc<Notify>,
+    echoes: Echoes,
+}
+
+/// Drives a [`MockWatcher`] from a test, cheap to clone so it can be handed to other tasks.
+#[derive(Clone)]
+pub struct MockWatcherHandle {
+    state: Arc<StdMutex<MockState>>,
+    wake: Arc<Notify>,
+}
+
+impl MockWatcher {
+    pub fn new() -> (Self, MockWatcherHandle) {
+        let state = Arc::new(StdMutex::new(MockState::default()));
+        let wake = Arc::new(Notify::new());
+        let handle = MockWatcherHandle {
+            state: state.clone(),
+            wake: wake.clone(),
+        };
+        let watcher = Self {
+            state,
+            wake,
+            echoes: Echoes::default(),
+        };
+        (watcher, handle)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().expect("mock watcher state poisoned")
+    }
+}
+
+impl MockWatcherHandle {
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().expect("mock watcher state poisoned")
+    }
+
+    /// The virtual clock, starts at the unix epoch
+    pub fn now(&self) -> SystemTime {
+        self.state().now()
+    }
+
+    /// Queues an event for the next recv, it's stamped with the virtual clock.
+    pub fn inject(&self, event: ChangeEvent) {
+        self.inject_after(Duration::ZERO, event);
+    }
+
+    /// Queues an event that's only received once the clock has been advanced past delay.
+    pub fn inject_after(&self, delay: Duration, event: ChangeEvent) {
+        let mut state = self.state();
+        let due = state.elapsed + delay;
+        let index = state.scheduled.partition_point(|(at, _)| *at <= due);
+        state.scheduled.insert(index, (due, event));
+        state.release_due();
+        drop(state);
+        self.wake.notify_one();
+    }
+
+    /// Steps the virtual clock forward, releasing any events that have come due.
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state();
+        state.elapsed += by;
+        state.release_due();
+        drop(state);
+        self.wake.notify_one();
+    }
+
+    /// Every call made on the watcher so far, in the order they were made
+    pub fn calls(&self) -> Vec<WatchCall> {
+        self.state().calls.clone()
+    }
+
+    /// Events injected that haven't been received yet, including ones not yet due
+    pub fn pending(&self) -> usize {
+        let state = self.state();
+        state.ready.len() + state.scheduled.len()
+    }
+
+    /// Once the queued events are drained recv returns None, like a watcher that's shut down.
+    pub fn close(&self) {
+        self.state().closed = true;
+        self.wake.notify_one();
+    }
+}
+
+#[async_trait]
+impl Watcher for MockWatcher {
+    async fn watch<H: Hasher + Default + Send>(&mut self, path: &Path) -> Result<()> {
+        let mut state = self.state();
+        state.calls.push(WatchCall::Watch(path.to_path_buf()));
+        if state.watching.iter().any(|root| root == path) {
+            return Result::Err(WatchError::AlreadyWatched(path.to_path_buf()).into());
+        }
+        state.watching.push(path.to_path_buf());
+        Ok(())
+    }
+
+    async fn unwatch(&mut self, path: &Path) -> Result<()> {
+        let mut state = self.state();
+        state.calls.push(WatchCall::Unwatch(path.to_path_buf()));
+        let Some(index) = state.watching.iter().position(|root| root == path) else {
+            return Result::Err(WatchError::NotWatched(path.to_path_buf()).into());
+        };
+        state.watching.remove(index);
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Option<ChangeEvent> {
+        loop {
+            // a wake up between checking the state and waiting is kept as a permit
+            let wake = self.wake.clone();
+            let event = {
+                let mut state = self.state();
+                match state.ready.pop_front() {
+                    Some(event) => Some(event),
+                    None if state.closed => return None,
+                    None => None,
+                }
+            };
+            match event {
+                Some(event) if !self.echoes.is_echo(&event) => return Some(event),
+                Some(_) => continue,
+                None => wake.notified().await,
+            }
+        }
+    }
+
+    async fn watched(&self) -> Vec<WatchedRoot> {
+        let state = self.state();
+        state
+            .watching
+            .iter()
+            .map(|root| WatchedRoot {
+                root: root.clone(),
+                status: WatchStatus::default(),
+            })
+            .collect()
+    }
+
+    fn expect_write(&mut self, path: &Path, object: Option<FileObject>) {
+        self.state()
+            .calls
+            .push(WatchCall::ExpectWrite(path.to_path_buf(), object));
+        self.echoes.expect(path, object);
+    }
+
+    fn forget_write(&mut self, path: &Path) {
+        self.state()
+            .calls
+            .push(WatchCall::ForgetWrite(path.to_path_buf()));
+        self.echoes.forget(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, block_on};
+    use notify::event::CreateKind;
+
+    fn project_dir(name: &str) -> (TempDir, HashMap<PathBuf, NotifyProject>) {
+        let root = TempDir::new(&format!("watcher-{name}"));
+        let mut projects = HashMap::new();
+        let watched = NotifyProject {
+            project: Project::new_global_or_default(&root),
+            read: FileObject::read::<seahash::SeaHasher>,
+            error: None,
+        };
+        projects.insert(root.to_path_buf(), watched);
+        (root, projects)
+    }
+
+    fn paths(events: &[ChangeEvent]) -> Vec<String> {
+        let mut paths = events
+            .iter()
+            .map(|event| format!("{:?}", event.kind))
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_poll_schedule_backs_off_when_idle() {
+        let mut schedule = PollSchedule::new(PollConfig::default());
+        let cost = Duration::from_millis(1);
+
+        assert_eq!(schedule.next(cost, false), Duration::from_secs(2));
+        assert_eq!(schedule.next(cost, false), Duration::from_secs(4));
+        for _ in 0..10 {
+            schedule.next(cost, false);
+        }
+        assert_eq!(schedule.next(cost, false), Duration::from_secs(30));
+        // busy again, straight back to scanning as often as we can
+        assert_eq!(schedule.next(cost, true), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_poll_schedule_stays_in_budget() {
+        let config = PollConfig {
+            cpu_budget: 0.1,
+            ..PollConfig::default()
+        };
+        let mut schedule = PollSchedule::new(config);
+
+        // a 2 second scan at 10% needs 18 seconds of rest, even while busy
+        let wait = schedule.next(Duration::from_secs(2), true);
+        assert_eq!(wait, Duration::from_secs(18));
+        // and expensive scans win over the longest idle wait
+        let wait = schedule.next(Duration::from_secs(10), false);
+        assert_eq!(wait, Duration::from_secs(90));
+    }
+
+    #[test]
+    fn test_echoes_swallow_only_matching_writes() {
+        let written = FileObject::from_bytes::<seahash::SeaHasher>(b"remote");
+        let edited = FileObject::from_bytes::<seahash::SeaHasher>(b"local");
+        let root = Path::new("/project");
+        let mut echoes = Echoes::default();
+        echoes.expect(&root.join("file.txt"), Some(written));
+
+        let modified = |object| {
+            ChangeEvent::new(
+                root,
+                ChangeKind::Modified(PathBuf::from("file.txt")),
+                object,
+            )
+        };
+        let other = ChangeEvent::new(root, ChangeKind::Created("other".into()), Some(written));
+
+        assert!(echoes.is_echo(&modified(Some(written))));
+        // a write can show up more than once, e.g. create followed by modify
+        assert!(echoes.is_echo(&modified(Some(written))));
+        assert!(!echoes.is_echo(&other));
+        assert!(!echoes.is_echo(&modified(Some(edited))));
+        // the user put back what we wrote, the server still has their edit so it has to go out
+        assert!(!echoes.is_echo(&modified(Some(written))));
+    }
+
+    #[test]
+    fn test_echoes_match_deletes_and_renames() {
+        let object = FileObject::from_bytes::<seahash::SeaHasher>(b"moved");
+        let root = Path::new("/project");
+        let mut echoes = Echoes::default();
+        echoes.expect(&root.join("gone"), None);
+        echoes.expect(&root.join("to"), Some(object));
+
+        let deleted = ChangeEvent::new(root, ChangeKind::Deleted("gone".into()), None);
+        let renamed = |from: &str| {
+            let kind = ChangeKind::Renamed {
+                from: from.into(),
+                to: "to".into(),
+            };
+            ChangeEvent::new(root, kind, Some(object))
+        };
+
+        assert!(echoes.is_echo(&deleted));
+        assert!(echoes.is_echo(&renamed("gone")));
+        assert!(!echoes.is_echo(&renamed("elsewhere")));
+    }
+
+    #[test]
+    fn test_created_directory_is_expanded() {
+        let (root, projects) = project_dir("created");
+        std::fs::create_dir_all(root.join("dir/nested")).unwrap();
+        std::fs::write(root.join("dir/a.txt"), "a").unwrap();
+        std::fs::write(root.join("dir/nested/b.txt"), "b").unwrap();
+
+        let mut handler = NotifyHandler::new();
+        let event =
+            notify::Event::new(EventKind::Create(CreateKind::Folder)).add_path(root.join("dir"));
+        let events = handler.handle(&projects, event);
+
+        assert_eq!(
+            paths(&events),
+            vec![r#"Created("dir/a.txt")"#, r#"Created("dir/nested/b.txt")"#]
+        );
+    }
+
+    #[test]
+    fn test_rename_halves_are_paired_once() {
+        let (root, projects) = project_dir("renamed");
+        std::fs::write(root.join("to.txt"), "content").unwrap();
+        let rename =
+            |mode| notify::Event::new(EventKind::Modify(ModifyKind::Name(mode))).set_tracker(7);
+
+        let mut handler = NotifyHandler::new();
+        let from = handler.handle(
+            &projects,
+            rename(RenameMode::From).add_path(root.join("from.txt")),
+        );
+        let to = handler.handle(
+            &projects,
+            rename(RenameMode::To).add_path(root.join("to.txt")),
+        );
+        let both = handler.handle(
+            &projects,
+            rename(RenameMode::Both)
+                .add_path(root.join("from.txt"))
+                .add_path(root.join("to.txt")),
+        );
+
+        assert!(from.is_empty());
+        assert_eq!(
+            paths(&to),
+            vec![r#"Renamed { from: "from.txt", to: "to.txt" }"#]
+        );
+        assert!(both.is_empty());
+    }
+
+    #[test]
+    fn test_rename_out_of_project_is_a_delete() {
+        let (root, projects) = project_dir("moved-out");
+        let mut handler = NotifyHandler::new();
+        let from = notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+            .set_tracker(3)
+            .add_path(root.join("gone.txt"));
+        let modify = notify::Event::new(EventKind::Remove(notify::event::RemoveKind::File))
+            .add_path(root.join("other.txt"));
+
+        assert!(handler.handle(&projects, from.clone()).is_empty());
+        assert_eq!(
+            paths(&handler.handle(&projects, modify)),
+            vec![r#"Deleted("gone.txt")"#, r#"Deleted("other.txt")"#]
+        );
+
+        // nothing else happens after it, like a move to the trash
+        assert!(handler.handle(&projects, from).is_empty());
+        let wait = Duration::from_secs(60);
+        assert!(handler.expire(&projects, wait).is_empty());
+        assert_eq!(
+            paths(&handler.expire(&projects, Duration::ZERO)),
+            vec![r#"Deleted("gone.txt")"#]
+        );
+        assert!(handler.expire(&projects, Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn test_directory_moved_out_deletes_each_file() {
+        let (root, projects) = project_dir("dir-moved-out");
+        std::fs::create_dir_all(root.join("dir/nested")).unwrap();
+        std::fs::write(root.join("dir/a.txt"), "a").unwrap();
+        std::fs::write(root.join("dir/nested/b.txt"), "b").unwrap();
+        std::fs::write(root.join("kept.txt"), "kept").unwrap();
+
+        let mut handler = NotifyHandler::new();
+        let project = &projects[root.as_ref()].project;
+        handler.track(&root, project.files_in(&root));
+        std::fs::remove_dir_all(root.join("dir")).unwrap();
+        let from = notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+            .set_tracker(5)
+            .add_path(root.join("dir"));
+
+        assert!(handler.handle(&projects, from).is_empty());
+        assert_eq!(
+            paths(&handler.expire(&projects, Duration::ZERO)),
+            vec![r#"Deleted("dir/a.txt")"#, r#"Deleted("dir/nested/b.txt")"#]
+        );
+    }
+
+    #[test]
+    fn test_hybrid_watcher_reports_changes_but_not_our_own() {
+        let root = TempDir::new("hybrid");
+        let events = block_on(async {
+            let mut watcher = HybridWatcher::new();
+            watcher.watch::<seahash::SeaHasher>(&root).await.unwrap();
+            std::fs::write(root.join("theirs.txt"), "theirs").unwrap();
+            // written the way remote changes are, to a temp file renamed into place
+            let ours = root.join("ours.txt");
+            let object = FileObject::from_bytes::<seahash::SeaHasher>(b"ours");
+            watcher.expect_write(&ours, Some(object));
+            let temp = root.join(format!(".ours.txt{}", crate::config::TEMP_SUFFIX));
+            std::fs::write(&temp, "ours").unwrap();
+            std::fs::rename(&temp, &ours).unwrap();
+
+            let mut events = HashSet::new();
+            let quiet = Duration::from_millis(RENAME_MILLIS * 2);
+            while let Result::Ok(Some(event)) = tokio::time::timeout(quiet, watcher.recv()).await {
+                events.extend(event.paths().into_iter().map(Path::to_path_buf));
+            }
+            events
+        });
+        // writing a file is a create and a modify, ours is neither
+        assert_eq!(events, HashSet::from([PathBuf::from("theirs.txt")]));
+    }
+
+    #[test]
+    fn test_mock_watcher_releases_events_on_the_virtual_clock() {
+        let (mut watcher, handle) = MockWatcher::new();
+        let root = PathBuf::from("/project");
+        let later = ChangeEvent::new(&root, ChangeKind::Deleted("b.txt".into()), None);
+        let now = ChangeEvent::new(&root, ChangeKind::Created("a.txt".into()), None);
+        handle.inject_after(Duration::from_secs(5), later);
+        handle.inject(now);
+
+        let first = block_on(watcher.recv()).unwrap();
+        assert_eq!(first.kind, ChangeKind::Created("a.txt".into()));
+        assert_eq!(first.at, SystemTime::UNIX_EPOCH);
+        assert_eq!(handle.pending(), 1);
+
+        handle.advance(Duration::from_secs(5));
+        let second = block_on(watcher.recv()).unwrap();
+        assert_eq!(second.kind, ChangeKind::Deleted("b.txt".into()));
+        assert_eq!(second.at, SystemTime::UNIX_EPOCH + Duration::from_secs(5));
+
+        handle.close();
+        assert!(block_on(watcher.recv()).is_none());
+    }
+
+    #[test]
+    fn test_mock_watcher_records_calls() {
+        let (mut watcher, handle) = MockWatcher::new();
+        let root = PathBuf::from("/project");
+        block_on(watcher.watch::<seahash::SeaHasher>(&root)).unwrap();
+        assert!(block_on(watcher.watch::<seahash::SeaHasher>(&root)).is_err());
+        watcher.expect_write(&root.join("a.txt"), None);
+        block_on(watcher.unwatch(&root)).unwrap();
+
+        assert_eq!(
+            handle.calls(),
+            vec![
+                WatchCall::Watch(root.clone()),
+                WatchCall::Watch(root.clone()),
+                WatchCall::ExpectWrite(root.join("a.txt"), None),
+                WatchCall::Unwatch(root.clone()),
+            ]
+        );
+        assert!(block_on(watcher.watched()).is_empty());
+
+        // our own delete is swallowed, the close lets recv finish instead of waiting forever
+        handle.inject(ChangeEvent::new(
+            &root,
+            ChangeKind::Deleted("a.txt".into()),
+            None,
+        ));
+        handle.close();
+        assert!(block_on(watcher.recv()).is_none());
+    }
 }
@@ -169,12 +169,12 @@ impl AppState {
     }
 
     pub async fn navigate_into_selected(&mut self) -> Result<()> {
-        if let Some(entry) = self.selected_entry() {
-            if entry.is_dir {
-                self.current_path = browser::navigate_into(&entry.path).await?;
-                self.refresh().await?;
-                self.update_preview().await?;
-            }
+        if let Some(entry) = self.selected_entry()
+            && entry.is_dir
+        {
+            self.current_path = browser::navigate_into(&entry.path).await?;
+            self.refresh().await?;
+            self.update_preview().await?;
         }
         Ok(())
     }
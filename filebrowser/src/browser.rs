@@ -40,9 +40,7 @@ pub async fn load_directory(path: &AsyncVfsPath) -> Result<Vec<DirEntry>> {
 /// Navigate to parent directory
 pub async fn navigate_up(path: &AsyncVfsPath) -> Result<AsyncVfsPath> {
     // Try to get parent, otherwise return current path (at root)
-    match path.parent() {
-        parent => Ok(parent),
-    }
+    Ok(path.parent())
 }
 
 /// Navigate into a directory
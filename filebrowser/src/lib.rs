@@ -22,7 +22,6 @@ pub async fn start_browser(
     add_quit: bool,
     mut log_rx: tokio::sync::mpsc::UnboundedReceiver<String>,
 ) -> Result<()> {
-
     let mut app = AppState::new(root, add_quit).await?;
 
     // Set up TUI
@@ -71,29 +70,27 @@ pub async fn start_browser(
 
 /// Signal handler for Ctrl+C and SIGTERM
 /// Returns a future that completes when a shutdown signal is received
-fn shutdown_signal() -> impl std::future::Future<Output = ()> {
-    async {
-        let ctrl_c = async {
-            signal::ctrl_c()
-                .await
-                .expect("failed to install Ctrl+C handler");
-        };
-
-        #[cfg(unix)]
-        let terminate = async {
-            signal::unix::signal(signal::unix::SignalKind::terminate())
-                .expect("failed to install SIGTERM handler")
-                .recv()
-                .await;
-        };
-
-        #[cfg(not(unix))]
-        let terminate = std::future::pending::<()>();
-
-        tokio::select! {
-            _ = ctrl_c => {},
-            _ = terminate => {},
-        }
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
 }
 
@@ -117,10 +114,10 @@ async fn handle_event(app: &mut AppState, event: Event, _add_quit: bool) -> Resu
             }
             KeyCode::Char('l') => {
                 // Navigate into selected directory
-                if let Some(entry) = app.selected_entry() {
-                    if entry.is_dir {
-                        app.navigate_into_selected().await?;
-                    }
+                if let Some(entry) = app.selected_entry()
+                    && entry.is_dir
+                {
+                    app.navigate_into_selected().await?;
                 }
                 Ok(false)
             }
@@ -1,25 +1,44 @@
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::{Router, response::IntoResponse, routing::get};
-use fastwebsockets::Frame;
 use fastwebsockets::OpCode;
 use fastwebsockets::upgrade;
+use fastwebsockets::{FragmentCollectorRead, Frame, Payload};
 use futures::stream::StreamExt;
 use futures::{AsyncWriteExt, FutureExt};
-use similar::DiffableStr;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::{
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     process::ExitCode,
 };
+use vfs::VfsError;
 use vfs::async_vfs::{AsyncMemoryFS, AsyncVfsPath};
+use vfs::error::VfsErrorKind;
 
 use anyhow::*;
-use core::messages::ServerMessage;
+use core::messages::{
+    self, ClientMessage, Envelope, ErrorCode, Hello, HelloReply, Response, ServerMessage, ToClient,
+};
+use core::patch::{self, StaleBase};
+use serde::Serialize;
+use streams::{Change, Missed, Streams};
+use tokio::io::AsyncRead;
 use tokio::net::*;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
 use tower_http::timeout::TimeoutLayer;
 use tracing_subscriber::prelude::*;
 
+mod streams;
+
+#[derive(Clone)]
+struct AppState {
+    root: AsyncVfsPath,
+    streams: Streams,
+}
+
 #[tokio::main]
 async fn main() -> Result<ExitCode> {
     let root: AsyncVfsPath = AsyncMemoryFS::new().into();
@@ -27,8 +46,12 @@ async fn main() -> Result<ExitCode> {
     tracing_subscriber::registry().with(capture_layer).init();
     let filebrowser_shutdown =
         tokio::spawn(filebrowser::start_browser(root.clone(), true, log_rx)).map(|_| ());
+    let state = AppState {
+        root,
+        streams: Streams::default(),
+    };
     let app = Router::new()
-        .route("/ws", get(ws_handler).with_state(root))
+        .route("/ws", get(ws_handler).with_state(state))
         .layer(TimeoutLayer::with_status_code(
             StatusCode::SERVICE_UNAVAILABLE,
             Duration::from_secs(1),
@@ -41,33 +64,43 @@ async fn main() -> Result<ExitCode> {
     return Ok(ExitCode::SUCCESS);
 }
 
-async fn handle_msg(vfs_path: &mut AsyncVfsPath, msg: ServerMessage) -> Result<()> {
+/// Applies a message to the server's copy, returning the change to share with the rest of the
+/// stream if there is one.
+async fn handle_msg(
+    vfs_path: &mut AsyncVfsPath,
+    msg: ServerMessage,
+) -> Result<Option<ClientMessage>> {
     match msg {
         ServerMessage::Create { path, content } => {
             if path.is_dir() {
                 return Err(anyhow!("can't create a directory"));
             }
-            let path = vfs_path.join(
+            let file_path = vfs_path.join(
                 path.to_str()
                     .ok_or(anyhow!("path does not exist on server"))?,
             )?;
-            path.parent().create_dir_all().await?;
-            let mut file = path.create_file().await?;
-            if let Some(content) = content {
+            file_path.parent().create_dir_all().await?;
+            let mut file = file_path.create_file().await?;
+            if let Some(content) = &content {
                 file.write_all(content.as_bytes()).await?;
             }
-            Ok(())
+            Ok(Some(ClientMessage::Create { path, content }))
         }
         ServerMessage::Delete { path } => {
             if path.is_dir() {
                 return Err(anyhow!("can't delete directory"));
             }
-            let path = vfs_path.join(
+            let file_path = vfs_path.join(
                 path.to_str()
                     .ok_or(anyhow!("path does not exist on server"))?,
             )?;
-            path.remove_file().await?;
-            let mut parent = path.parent();
+            let base = file_path
+                .read_to_string()
+                .await
+                .ok()
+                .map(|content| patch::version(&content));
+            file_path.remove_file().await?;
+            let mut parent = file_path.parent();
             while !parent.is_root() {
                 let count = parent.read_dir().await?.count().await;
                 if count == 0 {
@@ -77,16 +110,16 @@ async fn handle_msg(vfs_path: &mut AsyncVfsPath, msg: ServerMessage) -> Result<(
                 }
                 parent = parent.parent();
             }
-            Ok(())
-        }
-        // todo: modify should specify the a range of lines that it's changed.
-        // we'd then defer the commiting of the changes in the stream so we can accumulate diff's
-        // from multiple clients and resolve conflicts. This means that each stream will need a
-        // "projected" state for every participant, by this theres a second file structure that has
-        // the "floating" changes made by participants, when a conflict occur's it'll be surfaced
-        // to each client where they can resolve the issues. How this could look like is a file
-        // get's marked _dirty_ when a conflict occur's, this each participant then need's to
-        // resolve the issue in the conflicted area to enable the file's being synced again.
+            Ok(Some(ClientMessage::Delete { path, base }))
+        }
+        // todo: patches apply straight away, we'd instead defer the commiting of the changes in the
+        // stream so we can accumulate diff's from multiple clients and resolve conflicts. This
+        // means that each stream will need a "projected" state for every participant, by this
+        // theres a second file structure that has the "floating" changes made by participants, when
+        // a conflict occur's it'll be surfaced to each client where they can resolve the issues.
+        // How this could look like is a file get's marked _dirty_ when a conflict occur's, this
+        // each participant then need's to resolve the issue in the conflicted area to enable the
+        // file's being synced again.
         //
         // to faciliate this we send Response's back from the server that notify client's of
         // conflicting files so that the client can notify the user. Conflict's will not auto
@@ -117,13 +150,42 @@ async fn handle_msg(vfs_path: &mut AsyncVfsPath, msg: ServerMessage) -> Result<(
             if path.is_dir() {
                 return Err(anyhow!("can't create a directory"));
             }
-            let path = vfs_path.join(
+            let file_path = vfs_path.join(
                 path.to_str()
                     .ok_or(anyhow!("path does not exist on server"))?,
             )?;
-            let mut open_file = path.create_file().await?;
+            // the file's version before the change, so clients can tell if theirs has moved on
+            let base = file_path
+                .read_to_string()
+                .await
+                .ok()
+                .map(|content| patch::version(&content));
+            let mut open_file = file_path.create_file().await?;
             open_file.write_all(content.as_bytes()).await?;
-            Ok(())
+            Ok(Some(ClientMessage::Modify {
+                path,
+                content,
+                base,
+            }))
+        }
+        ServerMessage::Patch { path, base, hunks } => {
+            if path.is_dir() {
+                return Err(anyhow!("can't patch a directory"));
+            }
+            let file_path = vfs_path.join(
+                path.to_str()
+                    .ok_or(anyhow!("path does not exist on server"))?,
+            )?;
+            let content = file_path.read_to_string().await?;
+            let patched = patch::apply(&content, base, &hunks)?;
+            let mut open_file = file_path.create_file().await?;
+            open_file.write_all(patched.as_bytes()).await?;
+            // everyone else gets the whole file, their copies might not be at the patch's base
+            Ok(Some(ClientMessage::Modify {
+                path,
+                content: patched,
+                base: Some(base),
+            }))
         }
         ServerMessage::Project { root } => {
             if root.is_file() {
@@ -133,46 +195,285 @@ async fn handle_msg(vfs_path: &mut AsyncVfsPath, msg: ServerMessage) -> Result<(
                 root.to_str()
                     .ok_or(anyhow!("path does not exist on server"))?,
             )?;
-            Ok(())
+            Ok(None)
+        }
+        ServerMessage::Snapshot => Err(anyhow!("a snapshot doesn't change anything")),
+    }
+}
+
+/// Every file in the server's copy of the project at vfs_path, keyed by its path in the project.
+/// A project no one's sent anything for yet is empty.
+async fn snapshot(vfs_path: &AsyncVfsPath) -> Result<HashMap<PathBuf, String>> {
+    let mut files = HashMap::new();
+    if !vfs_path.exists().await? {
+        return Ok(files);
+    }
+    let prefix = format!("{}/", vfs_path.as_str());
+    let mut walk = vfs_path.walk_dir().await?;
+    while let Some(path) = walk.next().await {
+        let path = path?;
+        if !path.is_file().await? {
+            continue;
+        }
+        let relative = path
+            .as_str()
+            .strip_prefix(&prefix)
+            .ok_or(anyhow!("{} is outside of the project", path.as_str()))?;
+        files.insert(PathBuf::from(relative), path.read_to_string().await?);
+    }
+    Ok(files)
+}
+
+/// What to tell the client about the outcome of the message with the given id.
+fn respond(id: u64, path: Option<PathBuf>, result: Result<Option<u64>>) -> Response {
+    let err = match result {
+        Result::Ok(seq) => return Response::Ack { id, seq },
+        Err(err) => err,
+    };
+    if let (Some(stale), Some(path)) = (err.downcast_ref::<StaleBase>(), path) {
+        return Response::Conflict {
+            id,
+            path,
+            version: stale.found,
+        };
+    }
+    // only a failing disk is worth the client sending it again, the rest fail the same way every
+    // time
+    let code = match err.downcast_ref::<VfsError>().map(VfsError::kind) {
+        Some(VfsErrorKind::FileNotFound) => ErrorCode::NotFound,
+        Some(VfsErrorKind::IoError(_) | VfsErrorKind::AsyncIoError(_)) => ErrorCode::Internal,
+        _ => ErrorCode::Invalid,
+    };
+    tracing::warn!("rejected message {id}: {err}");
+    Response::Rejected {
+        id,
+        code,
+        message: err.to_string(),
+    }
+}
+
+/// Frames waiting to be written to a connection, everything that writes to the websocket goes
+/// through here so reading never has to wait on a write.
+type Outgoing = mpsc::Sender<Frame<'static>>;
+
+async fn send<T: Serialize>(outgoing: &Outgoing, id: u64, body: &T) -> Result<()> {
+    let payload = messages::encode(id, body)?;
+    outgoing
+        .send(Frame::binary(Payload::Owned(payload)))
+        .await
+        .map_err(|_| anyhow!("connection closed"))
+}
+
+/// Waits for the client's hello and answers it, None if the client was refused and disconnected.
+async fn handshake<S, F, R>(
+    ws: &mut FragmentCollectorRead<S>,
+    send_fn: &mut F,
+    outgoing: &Outgoing,
+) -> Result<Option<Hello>>
+where
+    S: AsyncRead + Unpin,
+    F: FnMut(Frame<'static>) -> R,
+    R: Future<Output = Result<()>>,
+{
+    let frame = ws.read_frame(send_fn).await?;
+    let (id, reply, hello) = match messages::decode::<Hello>(&frame.payload) {
+        Result::Ok(envelope) => {
+            let reply = envelope.body.reply(messages::FEATURES);
+            (envelope.id, reply, Some(envelope.body))
+        }
+        Err(err) => {
+            let reason = format!("expected a hello, is the client up to date? ({err})");
+            (0, HelloReply::Refused { reason }, None)
+        }
+    };
+    send(outgoing, id, &reply).await?;
+    match reply {
+        // only a hello that was read can be welcomed
+        HelloReply::Welcome { .. } => Ok(hello),
+        HelloReply::Refused { reason } => {
+            tracing::warn!("refused client: {reason}");
+            outgoing
+                .send(Frame::close(1002, reason.as_bytes()))
+                .await
+                .map_err(|_| anyhow!("connection closed"))?;
+            Ok(None)
         }
     }
 }
 
-async fn handle_client(vfs: AsyncVfsPath, fut: upgrade::UpgradeFut) -> Result<()> {
-    let mut ws = fastwebsockets::FragmentCollector::new(fut.await?);
-    let mut current_path = vfs.clone();
+/// Sends a change, preceded by the project it's in if that's not the one the last was in.
+async fn send_change(
+    outgoing: &Outgoing,
+    project: &mut Option<PathBuf>,
+    change: Change,
+) -> Result<()> {
+    if project.as_ref() != Some(&change.project) {
+        let message = ClientMessage::Project {
+            root: change.project.clone(),
+        };
+        send(outgoing, 0, &ToClient::Change(message)).await?;
+        *project = Some(change.project);
+    }
+    send(outgoing, change.seq, &ToClient::Change(change.message)).await
+}
+
+/// Sends the connection what it missed while it was away then every change the rest of the
+/// stream makes.
+async fn forward(
+    connection: u64,
+    missed: Missed,
+    mut changes: broadcast::Receiver<Change>,
+    outgoing: Outgoing,
+) -> Result<()> {
+    let mut project = None;
+    match missed {
+        Missed::Changes(missed) => {
+            for change in missed {
+                send_change(&outgoing, &mut project, change).await?;
+            }
+        }
+        Missed::Truncated { seq } => {
+            send(
+                &outgoing,
+                0,
+                &ToClient::Change(ClientMessage::Resync { seq }),
+            )
+            .await?;
+        }
+    }
+    let mut lagged = false;
     loop {
-        let frame = ws.read_frame().await?;
+        let change = match changes.recv().await {
+            Result::Ok(change) => change,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("connection {connection} missed {missed} changes");
+                lagged = true;
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        if std::mem::take(&mut lagged) {
+            let seq = change.seq - 1;
+            send(
+                &outgoing,
+                0,
+                &ToClient::Change(ClientMessage::Resync { seq }),
+            )
+            .await?;
+        }
+        if change.from != connection {
+            send_change(&outgoing, &mut project, change).await?;
+        }
+    }
+}
+
+async fn handle_client(state: AppState, fut: upgrade::UpgradeFut) -> Result<()> {
+    let (read, mut write) = fut.await?.split(tokio::io::split);
+    let mut ws = FragmentCollectorRead::new(read);
+    let (outgoing, mut frames) = mpsc::channel::<Frame<'static>>(100);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = frames.recv().await {
+            write.write_frame(frame).await?;
+        }
+        Ok(())
+    });
+    // pongs and closes the websocket has to answer with
+    let mut send_fn = |frame| {
+        let outgoing = outgoing.clone();
+        async move {
+            outgoing
+                .send(frame)
+                .await
+                .map_err(|_| anyhow!("connection closed"))
+        }
+    };
+
+    let Some(hello) = handshake(&mut ws, &mut send_fn, &outgoing).await? else {
+        drop(outgoing);
+        let _ = writer.await;
+        return Ok(());
+    };
+    let connection = state.streams.connection();
+    tracing::info!(
+        "{} connected to {} as {} ({connection})",
+        hello.user,
+        hello.stream,
+        hello.client_id
+    );
+    let (changes, missed) = state.streams.join(&hello.stream, hello.resume_from).await;
+    let forwarder = tokio::spawn(forward(connection, missed, changes, outgoing.clone()));
+
+    let mut current_path = state.root.clone();
+    let mut project = PathBuf::new();
+    let result = loop {
+        let frame = match ws.read_frame(&mut send_fn).await {
+            Result::Ok(frame) => frame,
+            Err(err) => break Err(err.into()),
+        };
         match frame.opcode {
-            OpCode::Close => break,
+            OpCode::Close => break Ok(()),
             OpCode::Text | OpCode::Binary => {
-                match &frame.payload {
-                    fastwebsockets::Payload::Bytes(bytes_mut) => {
-                        let str = bytes_mut.to_string_lossy();
-                        let msg: ServerMessage = (*str).try_into()?;
-                        handle_msg(&mut current_path, msg).await?;
+                let response = match messages::decode::<ServerMessage>(&frame.payload) {
+                    Result::Ok(Envelope { id, body }) => {
+                        let path = body.path().map(Path::to_path_buf);
+                        let root = match &body {
+                            ServerMessage::Project { root } => Some(root.clone()),
+                            _ => None,
+                        };
+                        let handled = match body {
+                            // only the client that asked gets it, it's not a change to the stream
+                            ServerMessage::Snapshot => match snapshot(&current_path).await {
+                                Result::Ok(files) => {
+                                    let message = ClientMessage::Snapshot { files };
+                                    send(&outgoing, 0, &ToClient::Change(message))
+                                        .await
+                                        .map(|()| None)
+                                }
+                                Err(err) => Err(err),
+                            },
+                            body => handle_msg(&mut current_path, body).await,
+                        };
+                        let result = match handled {
+                            Result::Ok(Some(message)) => {
+                                let seq = state
+                                    .streams
+                                    .publish(&hello.stream, connection, project.clone(), message)
+                                    .await;
+                                Result::Ok(Some(seq))
+                            }
+                            Result::Ok(None) => {
+                                project = root.unwrap_or(project);
+                                Result::Ok(None)
+                            }
+                            Err(err) => Err(err),
+                        };
+                        respond(id, path, result)
                     }
-                    _ => todo!(),
+                    Err(err) => Response::Rejected {
+                        id: 0,
+                        code: ErrorCode::Malformed,
+                        message: err.to_string(),
+                    },
                 };
-
-                let resp = Frame::new(
-                    false,
-                    OpCode::Binary,
-                    None,
-                    fastwebsockets::Payload::Bytes("yay".into()),
-                );
-                ws.write_frame(resp).await?;
+                if let Err(err) =
+                    send(&outgoing, response.id(), &ToClient::Response(response)).await
+                {
+                    break Err(err);
+                }
             }
             _ => {}
         }
-    }
+    };
 
-    Ok(())
+    forwarder.abort();
+    drop(outgoing);
+    let _ = writer.await;
+    result
 }
 
 async fn ws_handler(
     ws: upgrade::IncomingUpgrade,
-    State(state): State<AsyncVfsPath>,
+    State(state): State<AppState>,
 ) -> impl IntoResponse {
     let (response, fut) = ws.upgrade().unwrap();
 
@@ -184,3 +485,76 @@ async fn ws_handler(
 
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::testing::block_on;
+
+    #[test]
+    fn test_only_io_failures_are_worth_sending_again() {
+        let code = |kind: VfsErrorKind| match respond(1, None, Err(VfsError::from(kind).into())) {
+            Response::Rejected { code, .. } => code,
+            response => panic!("expected a rejection, got {response:?}"),
+        };
+        let io = std::io::Error::other("disk full");
+        assert_eq!(code(VfsErrorKind::IoError(io)), ErrorCode::Internal);
+        assert_eq!(code(VfsErrorKind::FileNotFound), ErrorCode::NotFound);
+        assert_eq!(code(VfsErrorKind::FileExists), ErrorCode::Invalid);
+        assert_eq!(code(VfsErrorKind::InvalidPath), ErrorCode::Invalid);
+    }
+
+    #[test]
+    fn test_a_truncated_join_is_told_to_resync_from_a_snapshot() {
+        block_on(async {
+            let root: AsyncVfsPath = AsyncMemoryFS::new().into();
+            let mut project = root.clone();
+            let create = |path: &str| ServerMessage::Create {
+                path: path.into(),
+                content: Some(format!("{path}\n")),
+            };
+            let project_message = ServerMessage::Project {
+                root: "shared".into(),
+            };
+            handle_msg(&mut project, project_message).await.unwrap();
+            handle_msg(&mut project, create("a.txt")).await.unwrap();
+            handle_msg(&mut project, create("src/b.txt")).await.unwrap();
+
+            let streams = Streams::default();
+            streams
+                .publish(
+                    "s",
+                    0,
+                    "shared".into(),
+                    ClientMessage::Create {
+                        path: "a.txt".into(),
+                        content: Some("a.txt\n".to_string()),
+                    },
+                )
+                .await;
+            // the server restarted since the client last saw change 7
+            let (changes, missed) = streams.join("s", Some(7)).await;
+            let (outgoing, mut frames) = mpsc::channel(10);
+            let forwarder = tokio::spawn(forward(1, missed, changes, outgoing));
+            let frame = frames.recv().await.unwrap();
+            let resync = messages::decode::<ToClient>(&frame.payload).unwrap().body;
+            assert_eq!(resync, ToClient::Change(ClientMessage::Resync { seq: 1 }));
+            forwarder.abort();
+
+            let files = snapshot(&project).await.unwrap();
+            assert_eq!(
+                files,
+                HashMap::from([
+                    ("a.txt".into(), "a.txt\n".to_string()),
+                    ("src/b.txt".into(), "src/b.txt\n".to_string()),
+                ])
+            );
+            assert!(
+                snapshot(&root.join("nothing").unwrap())
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+        });
+    }
+}
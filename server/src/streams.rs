@@ -0,0 +1,162 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use core::messages::ClientMessage;
+use tokio::sync::{Mutex, broadcast};
+
+/// How many changes a slow connection can fall behind by before it starts missing them
+const BACKLOG: usize = 1000;
+/// How many changes a stream remembers for clients resuming after a disconnect
+const LOG_LEN: usize = 10_000;
+
+/// A change the server accepted, shared with every other connection on the stream.
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// Position of the change in the stream, every change gets the next one
+    pub seq: u64,
+    /// The connection that made the change, it already has it
+    pub from: u64,
+    /// The project root the change was made in
+    pub project: PathBuf,
+    pub message: ClientMessage,
+}
+
+struct Stream {
+    sender: broadcast::Sender<Change>,
+    /// The most recent changes, oldest first
+    log: VecDeque<Change>,
+    next_seq: u64,
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(BACKLOG).0,
+            log: VecDeque::new(),
+            next_seq: 1,
+        }
+    }
+}
+
+/// What a resuming connection missed while it was away
+#[derive(Debug)]
+pub enum Missed {
+    Changes(Vec<Change>),
+    /// The log no longer goes back far enough, the client has to reconcile with the server's copy
+    /// and carry on from seq
+    Truncated {
+        seq: u64,
+    },
+}
+
+/// Every stream anyone has connected to
+#[derive(Clone, Default)]
+pub struct Streams {
+    streams: Arc<Mutex<HashMap<String, Stream>>>,
+    connections: Arc<AtomicU64>,
+}
+
+impl Streams {
+    /// A unique id for a new connection
+    pub fn connection(&self) -> u64 {
+        self.connections.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Joins a stream, starting it if no one's been on it. A client resuming from the last change
+    /// it saw gets the ones it missed, everything after them arrives on the receiver.
+    pub async fn join(
+        &self,
+        stream: &str,
+        resume_from: Option<u64>,
+    ) -> (broadcast::Receiver<Change>, Missed) {
+        let mut streams = self.streams.lock().await;
+        let stream = streams.entry(stream.to_string()).or_default();
+        let receiver = stream.sender.subscribe();
+        let Some(seen) = resume_from else {
+            return (receiver, Missed::Changes(Vec::new()));
+        };
+        let oldest = stream
+            .log
+            .front()
+            .map_or(stream.next_seq, |change| change.seq);
+        // a client ahead of us saw a stream from before the server restarted
+        if seen + 1 < oldest || seen >= stream.next_seq {
+            let seq = stream.next_seq - 1;
+            return (receiver, Missed::Truncated { seq });
+        }
+        let missed = stream
+            .log
+            .iter()
+            .filter(|change| change.seq > seen)
+            .cloned()
+            .collect();
+        (receiver, Missed::Changes(missed))
+    }
+
+    /// Shares a change with everyone on the stream, returning the sequence number it was given.
+    pub async fn publish(
+        &self,
+        stream: &str,
+        from: u64,
+        project: PathBuf,
+        message: ClientMessage,
+    ) -> u64 {
+        let mut streams = self.streams.lock().await;
+        let stream = streams.entry(stream.to_string()).or_default();
+        let change = Change {
+            seq: stream.next_seq,
+            from,
+            project,
+            message,
+        };
+        stream.next_seq += 1;
+        if stream.log.len() == LOG_LEN {
+            stream.log.pop_front();
+        }
+        stream.log.push_back(change.clone());
+        // no one else on the stream isn't a problem
+        let _ = stream.sender.send(change);
+        stream.next_seq - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::testing::block_on;
+
+    fn delete(path: &str) -> ClientMessage {
+        ClientMessage::Delete {
+            path: path.into(),
+            base: None,
+        }
+    }
+
+    #[test]
+    fn test_resuming_gets_missed_changes() {
+        block_on(async {
+            let streams = Streams::default();
+            for path in ["a", "b", "c"] {
+                streams.publish("s", 0, "p".into(), delete(path)).await;
+            }
+
+            let (_, missed) = streams.join("s", Some(1)).await;
+            let Missed::Changes(missed) = missed else {
+                panic!("log should reach back to 1");
+            };
+            assert_eq!(
+                missed.iter().map(|change| change.seq).collect::<Vec<_>>(),
+                vec![2, 3]
+            );
+
+            let (_, missed) = streams.join("s", Some(7)).await;
+            assert!(matches!(missed, Missed::Truncated { seq: 3 }));
+        });
+    }
+}